@@ -0,0 +1,131 @@
+use crate::device_auth::keystore;
+use crate::types::config::{self, Config};
+
+use std::env;
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+/// environment variable holding the passphrase the keystore is encrypted with by rotate-passphrase
+static NEW_PASSPHRASE_ENV: &str = "STREAMS_GATEWAY_NEW_KEYSTORE_PASSPHRASE";
+
+///
+/// Command line options, they take precedence over the environment variables and the configuration file
+///
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(
+    name = "streams-gateway",
+    about = "Publishes sensor data to IOTA Streams channels"
+)]
+pub struct Options {
+    /// Path of the configuration file (.json, .toml, .yaml or .yml)
+    #[structopt(short, long, default_value = "config.json", parse(from_os_str))]
+    pub config: PathBuf,
+    /// Port of the HTTP server
+    #[structopt(short, long)]
+    pub port: Option<u16>,
+    /// Address the servers are bound to
+    #[structopt(short, long)]
+    pub bind: Option<String>,
+    /// URL of the IOTA node, replaces the nodes of the configuration file
+    #[structopt(short, long)]
+    pub node: Option<String>,
+    /// Log level, e.g. info, debug or local=debug
+    #[structopt(short, long)]
+    pub log_level: Option<String>,
+    /// Accepts and logs the data without publishing it to the Tangle
+    #[structopt(long)]
+    pub dry_run: bool,
+    /// Validates the configuration and exits without starting the server
+    #[structopt(long)]
+    pub check_config: bool,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum Command {
+    /// Encrypts the keystore with a new passphrase, taken from STREAMS_GATEWAY_NEW_KEYSTORE_PASSPHRASE
+    /// or the provided file. The current passphrase is read like on startup
+    RotatePassphrase {
+        /// File containing the new passphrase
+        #[structopt(long, parse(from_os_str))]
+        new_passphrase_file: Option<PathBuf>,
+    },
+}
+
+impl Command {
+    ///
+    /// Executes the command, returns the message shown to the user
+    ///
+    pub fn run(&self, config: &Config) -> Result<String> {
+        match self {
+            Command::RotatePassphrase {
+                new_passphrase_file,
+            } => {
+                let new_passphrase = match (env::var(NEW_PASSPHRASE_ENV), new_passphrase_file) {
+                    (Ok(passphrase), _) if !passphrase.is_empty() => passphrase,
+                    (_, Some(file)) => keystore::read_passphrase(file)?,
+                    _ => {
+                        return Err(format!(
+                            "Set {} or --new-passphrase-file to rotate the passphrase",
+                            NEW_PASSPHRASE_ENV
+                        )
+                        .into())
+                    }
+                };
+                keystore::rotate_passphrase(config, &new_passphrase)?;
+                Ok(format!(
+                    "Keystore {} encrypted with the new passphrase, update {} or keystore_passphrase_file before restarting",
+                    config.keystore_path,
+                    keystore::PASSPHRASE_ENV
+                ))
+            }
+        }
+    }
+}
+
+impl Options {
+    ///
+    /// Overrides the configuration with the provided options
+    ///
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(bind) = &self.bind {
+            config.bind_address = bind.clone();
+        }
+        if let Some(node) = &self.node {
+            config.node = node.clone();
+            config.nodes.clear();
+        }
+        if let Some(log_level) = &self.log_level {
+            config.log_level = log_level.clone();
+        }
+        if self.dry_run {
+            config.dry_run = true;
+        }
+    }
+
+    ///
+    /// Reads the configuration file, applies the environment variables and the options and validates the result.
+    /// Returns every problem found if the configuration can't be used
+    ///
+    pub fn load_config(&self) -> std::result::Result<Config, Vec<String>> {
+        let mut config = Config::from_file(&self.config).map_err(|e| vec![e.to_string()])?;
+        config.apply_env().map_err(|e| vec![e.to_string()])?;
+        self.apply(&mut config);
+
+        let mut errors = config.validate();
+        errors.extend(config::check_permissions(&self.config, &config));
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce, Tag};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+/// cost of the key derivation, 2^14 iterations of scrypt
+const LOG_N: u8 = 14;
+const R: u32 = 8;
+const P: u32 = 1;
+/// the keystore is versioned to allow changing the parameters later on
+const VERSION: u8 = 1;
+/// bounds of the parameters read from a keystore, so a modified file can not make the derivation
+/// use more than 256 MiB of memory or run for minutes
+const LOG_N_RANGE: std::ops::RangeInclusive<u8> = 10..=20;
+const R_RANGE: std::ops::RangeInclusive<u32> = 1..=16;
+const P_RANGE: std::ops::RangeInclusive<u32> = 1..=4;
+const MAX_MEMORY: u64 = 256 * 1024 * 1024;
+
+///
+/// Keystore encrypted with AES-256-GCM, the key is derived from a passphrase with scrypt
+///
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EncryptedKeystore {
+    pub version: u8,
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub tag: String,
+    pub ciphertext: String,
+}
+
+///
+/// encrypts the provided plaintext with a new salt and nonce
+///
+pub fn encrypt(plaintext: &[u8], passphrase: &str) -> EncryptedKeystore {
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let key =
+        derive_key(passphrase, &salt, LOG_N, R, P).expect("default scrypt parameters are valid");
+    let mut ciphertext = plaintext.to_vec();
+    let tag = Aes256Gcm::new(&key.into())
+        .encrypt_in_place_detached(Nonce::from_slice(&nonce), &[VERSION], &mut ciphertext)
+        .expect("keystore fits into a single AES-GCM message");
+
+    EncryptedKeystore {
+        version: VERSION,
+        log_n: LOG_N,
+        r: R,
+        p: P,
+        salt: base64::encode(&salt),
+        nonce: base64::encode(&nonce),
+        tag: base64::encode(&tag),
+        ciphertext: base64::encode(&ciphertext),
+    }
+}
+
+///
+/// decrypts the keystore, fails if the passphrase is wrong or the file was modified
+///
+pub fn decrypt(encrypted: &EncryptedKeystore, passphrase: &str) -> Result<Vec<u8>> {
+    if encrypted.version != VERSION {
+        return Err(format!("Unsupported keystore version {}", encrypted.version).into());
+    }
+    let salt = base64::decode(&encrypted.salt)?;
+    let nonce = base64::decode(&encrypted.nonce)?;
+    let tag = base64::decode(&encrypted.tag)?;
+    let ciphertext = base64::decode(&encrypted.ciphertext)?;
+    if nonce.len() != 12 || tag.len() != 16 {
+        return Err("Malformed encrypted keystore".into());
+    }
+
+    let key = derive_key(passphrase, &salt, encrypted.log_n, encrypted.r, encrypted.p)?;
+    let mut plaintext = ciphertext;
+    if Aes256Gcm::new(&key.into())
+        .decrypt_in_place_detached(
+            Nonce::from_slice(&nonce),
+            &[encrypted.version],
+            &mut plaintext,
+            Tag::from_slice(&tag),
+        )
+        .is_err()
+    {
+        return Err("Could not decrypt keystore, wrong passphrase or corrupted file".into());
+    }
+    Ok(plaintext)
+}
+
+///
+/// derives the key with scrypt, parameters outside the fixed bounds are rejected
+///
+fn derive_key(passphrase: &str, salt: &[u8], log_n: u8, r: u32, p: u32) -> Result<[u8; 32]> {
+    // scrypt needs 128 * r * 2^log_n bytes of memory
+    let memory = (128 * r as u64)
+        .checked_shl(log_n as u32)
+        .unwrap_or(u64::MAX);
+    if !LOG_N_RANGE.contains(&log_n)
+        || !R_RANGE.contains(&r)
+        || !P_RANGE.contains(&p)
+        || memory > MAX_MEMORY
+    {
+        return Err(format!(
+            "Unsupported scrypt parameters log_n={} r={} p={} in encrypted keystore",
+            log_n, r, p
+        )
+        .into());
+    }
+    let params = scrypt::Params::new(log_n, r, p, 32)
+        .map_err(|e| format!("Invalid scrypt parameters: {}", e))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+        .map_err(|e| format!("Could not derive key: {}", e))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keystore_decrypts_with_its_passphrase_only() {
+        let encrypted = encrypt(b"{\"seeds\":{}}", "secret");
+        assert_eq!(decrypt(&encrypted, "secret").unwrap(), b"{\"seeds\":{}}");
+        assert!(decrypt(&encrypted, "other").is_err());
+    }
+
+    #[test]
+    fn scrypt_parameters_out_of_bounds_are_rejected() {
+        let valid = encrypt(b"{}", "secret");
+        for (log_n, r, p) in &[
+            (0, 8, 1),
+            (9, 8, 1),
+            (21, 8, 1),
+            (20, 16, 1),
+            (14, 0, 1),
+            (14, 8, 0),
+            (14, 8, 5),
+        ] {
+            let encrypted = EncryptedKeystore {
+                log_n: *log_n,
+                r: *r,
+                p: *p,
+                ..serde_json::from_value(serde_json::to_value(&valid).unwrap()).unwrap()
+            };
+            let error = decrypt(&encrypted, "secret").unwrap_err().to_string();
+            assert!(error.contains("Unsupported scrypt parameters"), "{}", error);
+        }
+    }
+}
@@ -1,54 +1,202 @@
+use crate::device_auth::encryption::{self, EncryptedKeystore};
+use crate::storage;
+use crate::types::config::Config;
+
 use crypto::digest::Digest;
 use crypto::sha3::Sha3;
 use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use std::fs::File;
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
 
-static PATH: &str = "src/device_auth/keystore.json";
+/// environment variable holding the passphrase of the keystore
+pub static PASSPHRASE_ENV: &str = "STREAMS_GATEWAY_KEYSTORE_PASSPHRASE";
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Keystore {
     pub api_keys_author: Vec<String>,
+    #[serde(default)]
+    pub api_keys_reader: Vec<String>,
+    /// seeds of the open channels by name, kept so their author can be recovered. They are not reused,
+    /// every start opens new channels
+    #[serde(default)]
+    pub seeds: HashMap<String, String>,
 }
 
 #[derive(Debug)]
 pub struct KeyManager {
     pub keystore: Keystore,
+    pub path: PathBuf,
 }
 
 impl KeyManager {
     ///
-    /// generates a new Keystore object by hashing the plaintext key and stroing it localy
+    /// generates a new Keystore object by hashing the plaintext key and stroing it with the channel seeds
+    /// at the provided path, encrypted if a passphrase is provided
     ///
-    pub fn new(new_keys_auth: Vec<String>) -> KeyManager {
+    pub fn new(
+        path: &Path,
+        passphrase: Option<&str>,
+        new_keys_auth: Vec<String>,
+        new_keys_reader: Vec<String>,
+        seeds: HashMap<String, String>,
+    ) -> Result<KeyManager> {
         let mut hash_list = vec![];
         for key in new_keys_auth {
             hash_list.push(calculate_hash(key));
         }
+        let mut reader_hash_list = vec![];
+        for key in new_keys_reader {
+            reader_hash_list.push(calculate_hash(key));
+        }
 
         let keystore = Keystore {
             api_keys_author: hash_list.clone(),
+            api_keys_reader: reader_hash_list,
+            seeds: seeds,
         };
 
-        store_keystore(&keystore);
+        store_keystore(path, &keystore, passphrase)?;
+
+        Ok(KeyManager {
+            keystore: keystore,
+            path: path.to_path_buf(),
+        })
+    }
 
-        KeyManager { keystore: keystore }
+    ///
+    /// restores the keystore from "keystore_path" if "restore_keystore" is set in the configuration,
+    /// otherwise generates it from the whitelisted ids. The seeds of the open channels, if provided,
+    /// replace the stored ones and are written in the same step as the keystore, so it is never stored without them
+    ///
+    pub fn from_config(
+        config: &Config,
+        seeds: Option<HashMap<String, String>>,
+    ) -> Result<KeyManager> {
+        let path = Path::new(&config.keystore_path);
+        let passphrase = passphrase(config)?;
+        if !config.restore_keystore {
+            return KeyManager::new(
+                path,
+                passphrase.as_deref(),
+                config.whitelisted_device_ids.clone(),
+                config.whitelisted_reader_ids.clone(),
+                seeds.unwrap_or_default(),
+            );
+        }
+        let mut manager = KeyManager::restore(path, passphrase.as_deref())?;
+        match seeds {
+            Some(seeds) if seeds != manager.keystore.seeds => {
+                manager.keystore.seeds = seeds;
+                manager.save(passphrase.as_deref())?;
+            }
+            _ => {}
+        }
+        Ok(manager)
     }
 
     ///
-    /// recreates the API key struct from local storeg
+    /// recreates the API key struct from the keystore stored at the provided path,
+    /// an encrypted keystore is decrypted with the passphrase
     ///
-    pub fn restore() -> KeyManager {
-        let rec: Keystore = serde_json::from_reader(File::open(PATH).unwrap()).unwrap();
-        KeyManager { keystore: rec }
+    pub fn restore(path: &Path, passphrase: Option<&str>) -> Result<KeyManager> {
+        let content = fs::read(path)
+            .map_err(|e| format!("Could not open keystore {}: {}", path.display(), e))?;
+        let parse_error = |e: GenericError| -> GenericError {
+            format!("Could not parse keystore {}: {}", path.display(), e).into()
+        };
+
+        let rec: Keystore = match serde_json::from_slice::<EncryptedKeystore>(&content) {
+            Ok(encrypted) => {
+                let passphrase = passphrase.ok_or_else(|| {
+                    format!(
+                        "Keystore {} is encrypted, set {} or keystore_passphrase_file",
+                        path.display(),
+                        PASSPHRASE_ENV
+                    )
+                })?;
+                let plaintext = encryption::decrypt(&encrypted, passphrase)?;
+                serde_json::from_slice(&plaintext).map_err(|e| parse_error(e.into()))?
+            }
+            Err(_) => serde_json::from_slice(&content).map_err(|e| parse_error(e.into()))?,
+        };
+        Ok(KeyManager {
+            keystore: rec,
+            path: path.to_path_buf(),
+        })
+    }
+
+    ///
+    /// stores the keystore again, encrypted if a passphrase is provided
+    ///
+    pub fn save(&self, passphrase: Option<&str>) -> Result<()> {
+        store_keystore(&self.path, &self.keystore, passphrase)
+    }
+}
+
+///
+/// Returns the passphrase of the keystore, taken from the STREAMS_GATEWAY_KEYSTORE_PASSPHRASE environment variable
+/// or the "keystore_passphrase_file" of the configuration. Without a passphrase the keystore is stored unencrypted
+///
+pub fn passphrase(config: &Config) -> Result<Option<String>> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        if passphrase.is_empty() {
+            return Err(format!("{} is empty", PASSPHRASE_ENV).into());
+        }
+        return Ok(Some(passphrase));
+    }
+    match &config.keystore_passphrase_file {
+        Some(file) => read_passphrase(Path::new(file)).map(Some),
+        None => Ok(None),
+    }
+}
+
+///
+/// encrypts the keystore of the configuration with a new passphrase, the keystore is decrypted with the current one
+///
+pub fn rotate_passphrase(config: &Config, new_passphrase: &str) -> Result<()> {
+    if new_passphrase.is_empty() {
+        return Err("The new passphrase is empty".into());
+    }
+    let path = Path::new(&config.keystore_path);
+    let current = passphrase(config)?;
+    KeyManager::restore(path, current.as_deref())?.save(Some(new_passphrase))
+}
+
+///
+/// reads a passphrase from a file, ignoring the trailing line break
+///
+pub fn read_passphrase(path: &Path) -> Result<String> {
+    let passphrase = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read passphrase {}: {}", path.display(), e))?;
+    let passphrase = passphrase.trim_end_matches(|c| c == '\n' || c == '\r');
+    if passphrase.is_empty() {
+        return Err(format!("Passphrase {} is empty", path.display()).into());
     }
+    Ok(passphrase.to_string())
 }
 
 ///
-/// stores the current keystore in a local file
+/// stores the keystore readable only by the owner, encrypted if a passphrase is provided.
+/// The keystore is written atomically so it is never left partially written
 ///
-fn store_keystore(keystore: &Keystore) -> () {
-    serde_json::to_writer(&File::create(PATH).unwrap(), keystore).unwrap();
+fn store_keystore(path: &Path, keystore: &Keystore, passphrase: Option<&str>) -> Result<()> {
+    let serialize = || -> Result<Vec<u8>> {
+        let plaintext = serde_json::to_vec(keystore)?;
+        Ok(match passphrase {
+            Some(passphrase) => serde_json::to_vec(&encryption::encrypt(&plaintext, passphrase))?,
+            None => plaintext,
+        })
+    };
+    serialize()
+        .and_then(|data| storage::write_atomic(path, &data, true).map_err(GenericError::from))
+        .map_err(|e| format!("Could not store keystore {}: {}", path.display(), e).into())
 }
 
 ///
@@ -64,6 +212,7 @@ pub fn calculate_hash(t: String) -> String {
 ///
 /// Verify that the key provided matches one of the whitelited hashes
 ///
+#[instrument(name = "auth", skip(key, hashes))]
 pub fn authenticate(key: &str, hashes: Vec<String>) -> bool {
     for hash in hashes {
         if calculate_hash(key.to_string()) == hash {
@@ -1,2 +1,4 @@
+///Encryption of the keystore at rest
+pub mod encryption;
 ///The struct used for storing and managing API keys
 pub mod keystore;
@@ -10,7 +10,13 @@
 )]
 #![cfg_attr(not(debug_assertions), deny(warnings))]
 extern crate gateway_core;
+pub mod cli;
 pub mod device_auth;
+pub mod monitoring;
+pub mod publishing;
+pub mod reload;
+pub mod shutdown;
+pub mod storage;
 pub mod types;
 pub mod wifi_connectivity;
 
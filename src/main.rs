@@ -1,33 +1,201 @@
-use gateway_core::gateway::publisher::Channel;
-use local::device_auth::keystore::KeyManager;
-use local::types::{channel_state::ChannelState, config::Config};
-use local::wifi_connectivity::http_server;
+use local::cli::Options;
+use local::device_auth::keystore::{self, KeyManager};
+use local::monitoring::{logging, metrics};
+use local::publishing::anchoring::{self, AnchorStore};
+use local::publishing::batcher::{self, Batch};
+use local::publishing::channel_history::ChannelHistory;
+use local::publishing::field_encryption::FieldEncryption;
+use local::publishing::node_pool::{self, NodePool};
+use local::publishing::retry::CircuitBreaker;
+use local::publishing::{rotation, routing};
+use local::reload;
+use local::shutdown;
+use local::types::channel_record::OpenReason;
+use local::types::channel_state::{ChannelState, PublishTarget};
+use local::types::gateway::Gateway;
+use local::wifi_connectivity::{coap_server, http_server, mqtt_client};
 
-use std::fs::File;
+use std::process;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+/// number of events buffered for slow /stream subscribers
+const FEED_CAPACITY: usize = 256;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    //read configuration file
-    let config: Config = serde_json::from_reader(File::open("config.json").unwrap()).unwrap();
+    let options = Options::from_args();
+
+    //read configuration file, overridden by the environment and the command line
+    let config = match options.load_config() {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("Invalid configuration {}:", options.config.display());
+            for error in errors {
+                eprintln!("  - {}", error);
+            }
+            process::exit(1);
+        }
+    };
+    if let Some(command) = &options.command {
+        match command.run(&config) {
+            Ok(message) => println!("{}", message),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if options.check_config {
+        println!("Configuration {} is valid", options.config.display());
+        return Ok(());
+    }
+
+    let mut store = match KeyManager::from_config(&config, None) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let anchors = match AnchorStore::open(&config) {
+        Ok(anchors) => anchors,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
 
-    let store = KeyManager::new(config.whitelisted_device_ids.clone());
+    let field_encryption = match FieldEncryption::from_config(&config) {
+        Ok(field_encryption) => field_encryption,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut history = match ChannelHistory::load(&config) {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    logging::init(&config.log_level, &config.log_format);
 
-    println!("Starting....");
+    info!("Starting....");
 
-    let mut channel = Channel::new(config.node.clone(), config.local_pow, None);
-    let (addr, msg_id) = match channel.open() {
-        Ok(a) => a,
+    metrics::register();
+
+    let nodes = Arc::new(Mutex::new(NodePool::new(&config)));
+    // every start opens new channels, reopening one with its stored seed would announce it again
+    // and reset the sequence state of its author
+    let seed = node_pool::generate_seed();
+    let (channel, channel_id, node) = match node_pool::open_channel(&nodes, &seed) {
+        Ok(opened) => opened,
         Err(_) => panic!("Could not connect to IOTA Node, try with another node!"),
     };
-    let channel_id = format!("{}:{}", addr, msg_id);
 
-    let channel_state = Arc::new(Mutex::new(ChannelState {
-        channel: channel,
-        channel_id: channel_id,
-    }));
+    let routes = match routing::open_routes(&nodes, &config) {
+        Ok(routes) => routes,
+        Err(_) => panic!("Could not connect to IOTA Node, try with another node!"),
+    };
+
+    history.opened(routing::DEFAULT_CHANNEL, &channel_id, OpenReason::Startup);
+    for (name, routed) in &routes {
+        history.opened(name, &routed.channel_id, OpenReason::Startup);
+    }
+
+    if config.dry_run {
+        warn!("Dry run, data is not published to the Tangle");
+    } else {
+        tokio::spawn(node_pool::probe(
+            nodes.clone(),
+            Duration::from_secs(config.node_probe_interval),
+        ));
+    }
+
+    let (feed, _) = broadcast::channel(FEED_CAPACITY);
+
+    let channel_state = ChannelState {
+        default: PublishTarget {
+            channel: channel,
+            channel_id: channel_id,
+            seed: seed,
+            node: node,
+        },
+        routes: routes,
+    };
+    store.keystore.seeds = channel_state.seeds();
+    if let Err(e) =
+        keystore::passphrase(&config).and_then(|passphrase| store.save(passphrase.as_deref()))
+    {
+        error!(error = %e, "could not store the channel seeds in the keystore");
+        process::exit(1);
+    }
+    let gateway = Arc::new(Gateway {
+        channel_state: Mutex::new(channel_state),
+        history: Mutex::new(history),
+        batch: Mutex::new(Batch::new(&config)),
+        anchors: Mutex::new(anchors),
+        nodes: nodes,
+        field_encryption: field_encryption,
+        retry: config.retry.clone(),
+        breaker: Mutex::new(CircuitBreaker::new(&config.retry)),
+        dry_run: config.dry_run,
+        feed: feed,
+        last_published: Mutex::new(None),
+    });
 
     let store = Arc::new(Mutex::new(store));
 
-    http_server::start(config, channel_state, store).await
+    if config.batching.is_some() {
+        tokio::spawn(batcher::run(gateway.clone(), config.clone()));
+    }
+
+    if config.anchoring.is_some() {
+        tokio::spawn(anchoring::run(gateway.clone(), config.clone()));
+    }
+
+    if config.channel_rotation.is_some() {
+        tokio::spawn(rotation::run(
+            gateway.clone(),
+            store.clone(),
+            config.clone(),
+        ));
+    }
+
+    tokio::spawn(reload::watch(options, config.clone(), store.clone()));
+
+    if config.mqtt.is_some() {
+        tokio::spawn(mqtt_client::start(
+            config.clone(),
+            gateway.clone(),
+            store.clone(),
+        ));
+    }
+
+    if let Some(port) = config.coap_port {
+        let coap = coap_server::start(config.clone(), gateway.clone(), store.clone(), port);
+        tokio::spawn(async move {
+            if let Err(e) = coap.await {
+                error!(error = %e, "CoAP server stopped");
+            }
+        });
+    }
+
+    tokio::spawn(shutdown::listen_for_signals());
+
+    http_server::start(config.clone(), gateway.clone(), store.clone()).await?;
+
+    // the lock is held until the process exits, so no message is published after draining
+    let _channel_state = shutdown::drain(&gateway, &store, &config).await;
+    info!("Stopped");
+    Ok(())
 }
@@ -0,0 +1,69 @@
+use crate::device_auth::keystore::KeyManager;
+use crate::publishing::node_pool;
+use crate::shutdown;
+use crate::timestamp_in_sec;
+use crate::types::gateway::Gateway;
+
+use std::sync::{Arc, Mutex};
+
+use hyper::{header, Body, Response, StatusCode};
+use serde_json::json;
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+///
+/// Handles the liveness request, returning 200 as long as the process is able to answer requests
+///
+pub async fn live_response() -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "status": "alive" }).to_string()))?)
+}
+
+///
+/// Handles the readiness request. The gateway is ready if the IOTA node accepts connections and at least one
+/// device is whitelisted in the keystore and no shutdown is in progress, otherwise 503 is returned. The report also contains the seconds since
+/// the last successful publish and the number of accepted readings waiting to be batched or anchored
+///
+pub async fn ready_response(
+    gateway: Arc<Gateway>,
+    keystore: Arc<Mutex<KeyManager>>,
+) -> Result<Response<Body>> {
+    let last_published = *gateway.last_published.lock().unwrap();
+    let backlog = gateway.batch.lock().unwrap().len() + gateway.anchors.lock().unwrap().pending();
+    let active_node = gateway.nodes.lock().unwrap().active.clone();
+    let node_reachable = node_pool::node_reachable(&active_node).await;
+    let devices = keystore
+        .lock()
+        .expect("lock keystore")
+        .keystore
+        .api_keys_author
+        .len();
+    let shutting_down = shutdown::is_requested();
+    let ready = node_reachable && devices > 0 && !shutting_down;
+
+    let report = json!({
+        "status": if shutting_down { "shutting down" } else if ready { "ready" } else { "not ready" },
+        "node": {
+            "url": active_node,
+            "reachable": node_reachable,
+        },
+        "seconds_since_last_publish": last_published.map(|t| timestamp_in_sec().saturating_sub(t)),
+        "queue_backlog": backlog,
+        "keystore": {
+            "loaded": devices > 0,
+            "devices": devices,
+        },
+    });
+
+    Ok(Response::builder()
+        .status(if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        })
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(report.to_string()))?)
+}
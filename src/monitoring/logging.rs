@@ -0,0 +1,58 @@
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tracing_subscriber::EnvFilter;
+
+type Reloader = Box<dyn Fn(EnvFilter) -> Result<(), String> + Send>;
+
+lazy_static! {
+    /// replaces the filter of the installed subscriber, set by init
+    static ref RELOAD: Mutex<Option<Reloader>> = Mutex::new(None);
+}
+
+///
+/// Installs the global subscriber writing the logs either as JSON or in a human readable format.
+/// The level accepts the same directives as the RUST_LOG environment variable, e.g. "info" or "local=debug"
+///
+pub fn init(level: &str, format: &str) {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::new(level));
+    match format {
+        "json" => {
+            let builder = builder.json().with_filter_reloading();
+            let handle = builder.reload_handle();
+            set_reloader(Box::new(move |filter| {
+                handle.reload(filter).map_err(|e| e.to_string())
+            }));
+            builder.init()
+        }
+        _ => {
+            let builder = builder.pretty().with_filter_reloading();
+            let handle = builder.reload_handle();
+            set_reloader(Box::new(move |filter| {
+                handle.reload(filter).map_err(|e| e.to_string())
+            }));
+            builder.init()
+        }
+    }
+}
+
+///
+/// changes the level of the running subscriber, the format can only be chosen at startup
+///
+pub fn set_level(level: &str) -> Result<(), String> {
+    match RELOAD.lock().unwrap().as_ref() {
+        Some(reload) => reload(EnvFilter::try_new(level).map_err(|e| e.to_string())?),
+        None => Err("logging is not initialized".to_string()),
+    }
+}
+
+fn set_reloader(reloader: Reloader) {
+    *RELOAD.lock().unwrap() = Some(reloader);
+}
+
+///
+/// generates a random id used to correlate the logs of a single request
+///
+pub fn request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
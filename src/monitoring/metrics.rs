@@ -0,0 +1,125 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+
+use hyper::{header, Body, Response, StatusCode};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+lazy_static! {
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "gateway_requests_total",
+        "Requests handled, by protocol, route and status",
+        &["protocol", "route", "status"]
+    )
+    .unwrap();
+    pub static ref IN_FLIGHT: IntGauge = register_int_gauge!(
+        "gateway_requests_in_flight",
+        "HTTP requests currently being handled"
+    )
+    .unwrap();
+    pub static ref AUTH_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "gateway_authorization_failures_total",
+        "Requests rejected because the device or reader is not whitelisted",
+        &["protocol", "route"]
+    )
+    .unwrap();
+    pub static ref PUBLISH_DURATION: Histogram = register_histogram!(
+        "gateway_publish_duration_seconds",
+        "Time spent publishing a signed message to the Tangle",
+        vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+    )
+    .unwrap();
+    pub static ref NODE_ERRORS: IntCounter = register_int_counter!(
+        "gateway_node_errors_total",
+        "Failed attempts to open a channel or publish a message on the IOTA node"
+    )
+    .unwrap();
+    pub static ref CHANNEL_SWITCHES: IntCounter = register_int_counter!(
+        "gateway_channel_switches_total",
+        "Channels opened to replace the current one"
+    )
+    .unwrap();
+    pub static ref BYTES_INGESTED: IntCounter = register_int_counter!(
+        "gateway_ingested_bytes_total",
+        "Bytes of data received from devices"
+    )
+    .unwrap();
+    pub static ref DEVICE_LAST_SEEN: IntGaugeVec = register_int_gauge_vec!(
+        "gateway_device_last_seen_timestamp_seconds",
+        "Time of the last accepted request of a device, identified by the hash it is published under",
+        &["device"]
+    )
+    .unwrap();
+}
+
+///
+/// registers all metrics, so they are reported before they are updated for the first time
+///
+pub fn register() {
+    lazy_static::initialize(&REQUESTS);
+    lazy_static::initialize(&IN_FLIGHT);
+    lazy_static::initialize(&AUTH_FAILURES);
+    lazy_static::initialize(&PUBLISH_DURATION);
+    lazy_static::initialize(&NODE_ERRORS);
+    lazy_static::initialize(&CHANNEL_SWITCHES);
+    lazy_static::initialize(&BYTES_INGESTED);
+    lazy_static::initialize(&DEVICE_LAST_SEEN);
+}
+
+///
+/// Counts a request as in flight until it is dropped, so requests ending with an error or a panic are not
+/// counted forever
+///
+pub struct InFlightGuard;
+
+impl InFlightGuard {
+    pub fn enter() -> InFlightGuard {
+        IN_FLIGHT.inc();
+        InFlightGuard
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.dec();
+    }
+}
+
+///
+/// counts a handled request, requests rejected with 401 are counted as authorization failures as well
+///
+pub fn record_request(protocol: &str, route: &str, status: StatusCode) {
+    REQUESTS
+        .with_label_values(&[protocol, route, status.as_str()])
+        .inc();
+    if status == StatusCode::UNAUTHORIZED {
+        AUTH_FAILURES.with_label_values(&[protocol, route]).inc();
+    }
+}
+
+///
+/// updates the last seen timestamp of the device with the provided hash
+///
+pub fn device_seen(device_hash: &str) {
+    DEVICE_LAST_SEEN
+        .with_label_values(&[device_hash])
+        .set(crate::timestamp_in_sec() as i64);
+}
+
+///
+/// Handles the metrics request returning all metrics in the Prometheus text format
+///
+pub async fn metrics_response() -> Result<Response<Body>> {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))?)
+}
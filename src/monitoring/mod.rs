@@ -0,0 +1,11 @@
+///
+/// Prometheus metrics collected by the gateway
+pub mod metrics;
+
+///
+/// structured logging of the gateway
+pub mod logging;
+
+///
+/// liveness and readiness checks
+pub mod health;
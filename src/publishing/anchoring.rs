@@ -0,0 +1,596 @@
+use crate::publishing::merkle;
+use crate::publishing::publisher;
+use crate::shutdown;
+use crate::storage;
+use crate::timestamp_in_sec;
+use crate::types::anchor::{
+    AnchorMessage, AnchorRecord, AnchoredBatch, InclusionProof, StoredReading,
+};
+use crate::types::{config::Config, gateway::Gateway, sensor_data::SensorData};
+use crate::wifi_connectivity::event_stream::notify;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{error, info, info_span, warn, Instrument};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+pub enum ProofLookup {
+    Anchored(InclusionProof),
+    Pending,
+    Unknown,
+}
+
+///
+/// Local store of the anchoring mode, every reading and every published root is appended to the store file
+/// so the proofs can still be served after a restart. Readings not yet anchored are picked up again on startup.
+/// Batches anchored more than "retention" seconds ago are dropped, the file is rewritten once it holds
+/// as many dropped records as kept ones
+///
+#[derive(Debug)]
+pub struct AnchorStore {
+    path: PathBuf,
+    file: Option<Arc<File>>,
+    retention: u64,
+    readings: HashMap<String, StoredReading>,
+    pending: Vec<String>,
+    batches: Vec<AnchoredBatch>,
+    anchored: HashMap<String, (usize, usize)>,
+    /// records in the store file which were dropped
+    stale: usize,
+}
+
+impl AnchorStore {
+    ///
+    /// opens the store file of the anchoring mode, without anchoring configured the store stays empty
+    ///
+    pub fn open(config: &Config) -> Result<AnchorStore> {
+        let mut store = AnchorStore {
+            path: PathBuf::new(),
+            file: None,
+            retention: 0,
+            readings: HashMap::new(),
+            pending: vec![],
+            batches: vec![],
+            anchored: HashMap::new(),
+            stale: 0,
+        };
+        let anchoring = match &config.anchoring {
+            Some(anchoring) => anchoring,
+            None => return Ok(store),
+        };
+        store.path = PathBuf::from(&anchoring.store_path);
+        store.retention = anchoring.retention;
+        store.load().map_err(|e| {
+            format!(
+                "Could not read anchor store {}: {}",
+                store.path.display(),
+                e
+            )
+        })?;
+        store.reopen().map_err(|e| {
+            format!(
+                "Could not open anchor store {}: {}",
+                store.path.display(),
+                e
+            )
+        })?;
+        Ok(store)
+    }
+
+    fn reopen(&mut self) -> std::io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.file = Some(Arc::new(file));
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<()> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line)? {
+                AnchorRecord::Reading(reading) => {
+                    self.pending.push(reading.reading_id.clone());
+                    self.readings.insert(reading.reading_id.clone(), reading);
+                }
+                AnchorRecord::Anchored(batch) => {
+                    let missing = batch
+                        .reading_ids
+                        .iter()
+                        .filter(|id| !self.readings.contains_key(*id))
+                        .count();
+                    if missing == 0 {
+                        self.index(batch);
+                        continue;
+                    }
+                    // a batch without its readings can not prove anything, its other readings are anchored again
+                    warn!(
+                        merkle_root = %batch.merkle_root,
+                        missing,
+                        "anchored batch without its readings skipped"
+                    );
+                    self.stale += 1;
+                }
+            }
+        }
+        if !self.pending.is_empty() {
+            info!(
+                readings = self.pending.len(),
+                "readings waiting for anchoring"
+            );
+        }
+        Ok(())
+    }
+
+    ///
+    /// appends the records to the store file, they are on disk once the file was synced
+    ///
+    fn append(&mut self, records: &[AnchorRecord]) -> Result<()> {
+        let mut file: &File = self.file.as_ref().ok_or("anchoring is not configured")?;
+        let mut lines = vec![];
+        for record in records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+        file.write_all(&lines)?;
+        Ok(())
+    }
+
+    ///
+    /// the store file to sync after appending, outside the lock of the store
+    ///
+    fn file(&self) -> Result<Arc<File>> {
+        Ok(self.file.clone().ok_or("anchoring is not configured")?)
+    }
+
+    fn index(&mut self, batch: AnchoredBatch) {
+        let position = self.batches.len();
+        for (leaf, id) in batch.reading_ids.iter().enumerate() {
+            self.anchored.insert(id.clone(), (position, leaf));
+        }
+        let anchored = &self.anchored;
+        self.pending.retain(|id| !anchored.contains_key(id));
+        self.batches.push(batch);
+    }
+
+    ///
+    /// stores the readings until the next anchoring, returning their ids
+    ///
+    fn push(&mut self, readings: Vec<SensorData>) -> Result<Vec<String>> {
+        let received_at = timestamp_in_sec();
+        let stored: Vec<StoredReading> = readings
+            .into_iter()
+            .map(|reading| StoredReading {
+                reading_id: format!("{:016x}", rand::random::<u64>()),
+                received_at: received_at,
+                reading: reading,
+            })
+            .collect();
+        let records: Vec<AnchorRecord> =
+            stored.iter().cloned().map(AnchorRecord::Reading).collect();
+        self.append(&records)?;
+        let mut ids = vec![];
+        for reading in stored {
+            ids.push(reading.reading_id.clone());
+            self.pending.push(reading.reading_id.clone());
+            self.readings.insert(reading.reading_id.clone(), reading);
+        }
+        Ok(ids)
+    }
+
+    ///
+    /// number of readings waiting for the next anchoring
+    ///
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    ///
+    /// drops the batches anchored more than "retention" seconds ago with their readings, readings waiting
+    /// for anchoring are always kept. Returns the number of dropped batches
+    ///
+    pub fn expire(&mut self) -> Result<usize> {
+        let cutoff = timestamp_in_sec().saturating_sub(self.retention);
+        let (expired, kept): (Vec<AnchoredBatch>, Vec<AnchoredBatch>) =
+            std::mem::take(&mut self.batches)
+                .into_iter()
+                .partition(|batch| batch.anchored_at < cutoff);
+        if expired.is_empty() {
+            self.batches = kept;
+            return Ok(0);
+        }
+
+        self.anchored.clear();
+        for batch in kept {
+            self.index(batch);
+        }
+        let before = self.readings.len();
+        let pending: HashSet<&String> = self.pending.iter().collect();
+        let anchored = &self.anchored;
+        self.readings
+            .retain(|id, _| anchored.contains_key(id) || pending.contains(id));
+        self.stale += expired.len() + before - self.readings.len();
+
+        if self.stale >= self.readings.len() + self.batches.len() {
+            self.compact()?;
+        }
+        Ok(expired.len())
+    }
+
+    ///
+    /// rewrites the store file with the kept records only
+    ///
+    fn compact(&mut self) -> Result<()> {
+        let mut written = HashSet::new();
+        let mut data = vec![];
+        let mut write = |record: &AnchorRecord| -> Result<()> {
+            serde_json::to_writer(&mut data, record)?;
+            data.push(b'\n');
+            Ok(())
+        };
+        for batch in &self.batches {
+            for id in &batch.reading_ids {
+                if let Some(reading) = self.readings.get(id) {
+                    if written.insert(id) {
+                        write(&AnchorRecord::Reading(reading.clone()))?;
+                    }
+                }
+            }
+            write(&AnchorRecord::Anchored(batch.clone()))?;
+        }
+        for id in &self.pending {
+            if let Some(reading) = self.readings.get(id) {
+                if written.insert(id) {
+                    write(&AnchorRecord::Reading(reading.clone()))?;
+                }
+            }
+        }
+        storage::write_atomic(&self.path, &data, false)?;
+        self.reopen()?;
+        info!(dropped = self.stale, "anchor store compacted");
+        self.stale = 0;
+        Ok(())
+    }
+
+    ///
+    /// the inclusion proof of an anchored reading
+    ///
+    pub fn proof(&self, id: &str) -> ProofLookup {
+        let (position, index) = match self.anchored.get(id) {
+            Some(found) => *found,
+            None if self.readings.contains_key(id) => return ProofLookup::Pending,
+            None => return ProofLookup::Unknown,
+        };
+        let batch = &self.batches[position];
+        let (reading, leaves) = match (self.readings.get(id), self.leaves(&batch.reading_ids)) {
+            (Some(reading), Some(leaves)) => (reading, leaves),
+            _ => return ProofLookup::Unknown,
+        };
+        ProofLookup::Anchored(InclusionProof {
+            reading_id: id.to_string(),
+            reading: reading.reading.clone(),
+            leaf: leaves[index].clone(),
+            path: merkle::path(&leaves, index),
+            merkle_root: batch.merkle_root.clone(),
+            channel_id: batch.channel_id.clone(),
+            message_link: batch.message_link.clone(),
+            anchored_at: batch.anchored_at,
+        })
+    }
+
+    ///
+    /// the leaf hashes of the readings, None if one of them is not stored
+    ///
+    fn leaves(&self, ids: &[String]) -> Option<Vec<String>> {
+        ids.iter()
+            .map(|id| {
+                self.readings
+                    .get(id)
+                    .map(|stored| merkle::leaf_hash(&stored.reading))
+            })
+            .collect()
+    }
+}
+
+///
+/// Publishes the Merkle root of the readings stored since the last anchoring, the readings are pushed to
+/// the live feed once their root is stored. On failure the readings stay pending and are anchored with the next batch
+///
+pub async fn anchor(gateway: &Arc<Gateway>) {
+    let (ids, root) = {
+        let anchors = gateway.anchors.lock().unwrap();
+        let ids = anchors.pending.clone();
+        match anchors
+            .leaves(&ids)
+            .and_then(|leaves| merkle::root(&leaves))
+        {
+            Some(root) => (ids, root),
+            None => return,
+        }
+    };
+    let span = info_span!("anchor", readings = ids.len());
+    publish_root(gateway, ids, root).instrument(span).await
+}
+
+async fn publish_root(gateway: &Arc<Gateway>, ids: Vec<String>, root: String) {
+    let message = AnchorMessage {
+        merkle_root: root.clone(),
+        readings: ids.len(),
+        anchored_at: timestamp_in_sec(),
+    };
+    let published = match publisher::write_signed(gateway, None, &message).await {
+        Ok(published) => published,
+        Err(e) => {
+            error!(error = %e, "could not publish Merkle root to IOTA node");
+            return;
+        }
+    };
+
+    let batch = AnchoredBatch {
+        merkle_root: root,
+        channel_id: published.channel_id,
+        message_link: published.link,
+        anchored_at: message.anchored_at,
+        reading_ids: ids,
+    };
+    let merkle_root = batch.merkle_root.clone();
+    let channel_id = batch.channel_id.clone();
+    let (stored, readings) = {
+        let mut anchors = gateway.anchors.lock().unwrap();
+        let stored = anchors
+            .append(&[AnchorRecord::Anchored(batch.clone())])
+            .and_then(|_| anchors.file());
+        let readings: Vec<SensorData> = match stored {
+            Ok(_) => batch
+                .reading_ids
+                .iter()
+                .filter_map(|id| anchors.readings.get(id))
+                .map(|stored| stored.reading.clone())
+                .collect(),
+            Err(_) => vec![],
+        };
+        if stored.is_ok() {
+            anchors.index(batch);
+        }
+        (stored, readings)
+    };
+    let synced = match stored {
+        Ok(file) => sync(file).await,
+        Err(e) => {
+            warn!(error = %e, "Merkle root published but not stored, the readings are anchored again");
+            return;
+        }
+    };
+    if let Err(e) = synced {
+        warn!(error = %e, "could not sync the anchor store");
+    }
+    for reading in &readings {
+        notify(gateway, &channel_id, reading, true);
+    }
+    info!(merkle_root = %merkle_root, "readings anchored");
+}
+
+///
+/// Stores the readings until the next anchoring, returning their ids. The records are appended under the lock
+/// of the store and synced on a blocking thread, one sync covers every record appended before it
+///
+pub async fn store(gateway: &Arc<Gateway>, readings: Vec<SensorData>) -> Result<Vec<String>> {
+    let (ids, file) = {
+        let mut anchors = gateway.anchors.lock().unwrap();
+        let ids = anchors.push(readings)?;
+        (ids, anchors.file()?)
+    };
+    sync(file).await?;
+    Ok(ids)
+}
+
+async fn sync(file: Arc<File>) -> Result<()> {
+    tokio::task::spawn_blocking(move || file.sync_data()).await??;
+    Ok(())
+}
+
+///
+/// Drops the expired batches on a blocking thread, as the store file may be rewritten
+///
+async fn expire(gateway: &Arc<Gateway>) {
+    let gateway = gateway.clone();
+    let expired =
+        tokio::task::spawn_blocking(move || gateway.anchors.lock().unwrap().expire()).await;
+    match expired {
+        Ok(Ok(0)) => {}
+        Ok(Ok(batches)) => info!(batches, "expired anchored batches dropped"),
+        Ok(Err(e)) => error!(error = %e, "could not compact the anchor store"),
+        Err(e) => error!(error = %e, "could not compact the anchor store"),
+    }
+}
+
+///
+/// Anchors the stored readings every "interval" seconds until the shutdown is requested
+///
+pub async fn run(gateway: Arc<Gateway>, config: Config) {
+    let interval = match &config.anchoring {
+        Some(anchoring) => Duration::from_secs(anchoring.interval),
+        None => return,
+    };
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                anchor(&gateway).await;
+                expire(&gateway).await;
+            }
+            _ = shutdown::requested() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str, retention: u64) -> AnchorStore {
+        let path =
+            std::env::temp_dir().join(format!("anchors-{}-{}.jsonl", name, rand::random::<u32>()));
+        let mut store = AnchorStore {
+            path: path,
+            file: None,
+            retention: retention,
+            readings: HashMap::new(),
+            pending: vec![],
+            batches: vec![],
+            anchored: HashMap::new(),
+            stale: 0,
+        };
+        store.reopen().expect("open store");
+        store
+    }
+
+    fn reading() -> SensorData {
+        serde_json::from_value(serde_json::json!({
+            "iot2tangle": [{"sensor": "Env", "data": [{"Temp": "21"}]}],
+            "device": "DEVICE_ID_1",
+            "timestamp": 1600000000,
+        }))
+        .expect("reading")
+    }
+
+    fn anchor_pending(store: &mut AnchorStore, anchored_at: u64) {
+        let ids = store.pending.clone();
+        let batch = AnchoredBatch {
+            merkle_root: merkle::root(&store.leaves(&ids).expect("leaves")).expect("root"),
+            channel_id: "channel".to_string(),
+            message_link: "link".to_string(),
+            anchored_at: anchored_at,
+            reading_ids: ids,
+        };
+        store
+            .append(&[AnchorRecord::Anchored(batch.clone())])
+            .expect("append");
+        store.index(batch);
+    }
+
+    fn reload(store: &AnchorStore) -> AnchorStore {
+        let mut reloaded = AnchorStore {
+            path: store.path.clone(),
+            file: None,
+            retention: store.retention,
+            readings: HashMap::new(),
+            pending: vec![],
+            batches: vec![],
+            anchored: HashMap::new(),
+            stale: 0,
+        };
+        reloaded.load().expect("load store");
+        reloaded
+    }
+
+    #[test]
+    fn readings_and_proofs_survive_a_restart() {
+        let mut store = store("restart", 3600);
+        let anchored = store.push(vec![reading(), reading()]).expect("push");
+        anchor_pending(&mut store, timestamp_in_sec());
+        let pending = store.push(vec![reading()]).expect("push");
+
+        let reloaded = reload(&store);
+        assert_eq!(reloaded.pending, pending);
+        match reloaded.proof(&anchored[1]) {
+            ProofLookup::Anchored(proof) => assert!(merkle::verify(&proof)),
+            _ => panic!("reading not anchored"),
+        }
+        std::fs::remove_file(&store.path).ok();
+    }
+
+    #[test]
+    fn expired_batches_are_dropped_and_the_file_compacted() {
+        let mut store = store("expire", 3600);
+        let expired = store.push(vec![reading(), reading()]).expect("push");
+        anchor_pending(&mut store, timestamp_in_sec() - 7200);
+        let kept = store.push(vec![reading()]).expect("push");
+        anchor_pending(&mut store, timestamp_in_sec());
+        let pending = store.push(vec![reading()]).expect("push");
+
+        assert_eq!(store.expire().expect("expire"), 1);
+        assert!(matches!(store.proof(&expired[0]), ProofLookup::Unknown));
+        assert!(matches!(store.proof(&kept[0]), ProofLookup::Anchored(_)));
+        assert!(matches!(store.proof(&pending[0]), ProofLookup::Pending));
+        // one batch and two readings dropped, one batch and two readings kept
+        assert_eq!(store.stale, 0);
+        let lines = std::fs::read_to_string(&store.path).expect("read store");
+        assert_eq!(lines.lines().count(), 3);
+
+        // records appended after the compaction go to the new file
+        let added = store.push(vec![reading()]).expect("push");
+        let reloaded = reload(&store);
+        assert_eq!(reloaded.pending, vec![pending[0].clone(), added[0].clone()]);
+        match reloaded.proof(&kept[0]) {
+            ProofLookup::Anchored(proof) => assert!(merkle::verify(&proof)),
+            _ => panic!("reading not anchored"),
+        }
+        assert!(matches!(reloaded.proof(&expired[0]), ProofLookup::Unknown));
+        std::fs::remove_file(&store.path).ok();
+    }
+
+    #[test]
+    fn file_is_rewritten_once_half_of_it_is_stale() {
+        let mut store = store("stale", 3600);
+        store.push(vec![reading()]).expect("push");
+        anchor_pending(&mut store, timestamp_in_sec() - 7200);
+        for _ in 0..3 {
+            store.push(vec![reading()]).expect("push");
+            anchor_pending(&mut store, timestamp_in_sec());
+        }
+        assert_eq!(store.expire().expect("expire"), 1);
+        // two dropped records against six kept ones
+        assert_eq!(store.stale, 2);
+        let lines = std::fs::read_to_string(&store.path).expect("read store");
+        assert_eq!(lines.lines().count(), 8);
+        std::fs::remove_file(&store.path).ok();
+    }
+
+    #[test]
+    fn batches_without_their_readings_are_skipped() {
+        let mut store = store("orphaned", 3600);
+        let orphaned = store.push(vec![reading(), reading()]).expect("push");
+        anchor_pending(&mut store, timestamp_in_sec());
+        let kept = store.push(vec![reading()]).expect("push");
+        anchor_pending(&mut store, timestamp_in_sec());
+
+        // the store loses the record of a reading of the first batch
+        let lines: Vec<String> = std::fs::read_to_string(&store.path)
+            .expect("read store")
+            .lines()
+            .filter(|line| !line.contains(&format!("\"reading_id\":\"{}\"", orphaned[0])))
+            .map(|line| line.to_string())
+            .collect();
+        std::fs::write(&store.path, lines.join("\n") + "\n").expect("write store");
+
+        let reloaded = reload(&store);
+        assert_eq!(reloaded.batches.len(), 1);
+        assert_eq!(reloaded.stale, 1);
+        assert!(matches!(reloaded.proof(&orphaned[0]), ProofLookup::Unknown));
+        // the remaining reading of the skipped batch is anchored again
+        assert!(matches!(reloaded.proof(&orphaned[1]), ProofLookup::Pending));
+        assert_eq!(reloaded.pending, vec![orphaned[1].clone()]);
+        match reloaded.proof(&kept[0]) {
+            ProofLookup::Anchored(proof) => assert!(merkle::verify(&proof)),
+            _ => panic!("reading not anchored"),
+        }
+        std::fs::remove_file(&store.path).ok();
+    }
+}
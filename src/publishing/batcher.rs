@@ -0,0 +1,526 @@
+use crate::publishing::publisher;
+use crate::publishing::splitter::{self, BundleError, PartialBundle, PublishedBundle};
+use crate::shutdown;
+use crate::timestamp_in_sec;
+use crate::types::{
+    bundle_data::BundleData, bundle_part::BundlePart, config::Config, gateway::Gateway,
+    sensor_data::SensorData,
+};
+use crate::wifi_connectivity::event_stream::notify;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_derive::Serialize;
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// answer to readings rejected because the batch is full
+pub static BATCH_FULL: &str = "Too many readings waiting to be published, try again later!";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptStatus {
+    Pending,
+    Published,
+    Failed,
+}
+
+///
+/// State of a reading accepted in batching mode, returned to the device and available on /receipts/{id}
+///
+#[derive(Serialize, Debug, Clone)]
+pub struct Receipt {
+    pub receipt: String,
+    pub status: ReceiptStatus,
+    pub received_at: u64,
+    pub channel_id: Option<String>,
+    pub bundle_id: Option<String>,
+    pub messages: Vec<String>,
+}
+
+///
+/// Readings waiting to be published as a bundle, and the receipts of the readings handled so far.
+/// At most "max_pending" readings are kept, counting those being published and those of unfinished bundles
+///
+#[derive(Debug)]
+pub struct Batch {
+    pending: Vec<(String, SensorData)>,
+    unfinished: Vec<UnfinishedBundle>,
+    /// readings taken by the flush in progress
+    in_flight: usize,
+    receipts: HashMap<String, Receipt>,
+    receipt_ttl: u64,
+    max_pending: usize,
+    /// held while flushing, so only one flush publishes at a time
+    flushing: Arc<AsyncMutex<()>>,
+}
+
+impl Batch {
+    pub fn new(config: &Config) -> Batch {
+        let (receipt_ttl, max_pending) = config
+            .batching
+            .as_ref()
+            .map(|batching| (batching.receipt_ttl, batching.max_pending))
+            .unwrap_or((0, 0));
+        Batch {
+            pending: vec![],
+            unfinished: vec![],
+            in_flight: 0,
+            receipts: HashMap::new(),
+            receipt_ttl: receipt_ttl,
+            max_pending: max_pending,
+            flushing: Arc::new(AsyncMutex::new(())),
+        }
+    }
+
+    ///
+    /// adds the reading to the next bundle, returning its pending receipt. Returns None if "max_pending"
+    /// readings are already waiting
+    ///
+    pub fn push(&mut self, sensor_data: SensorData) -> Option<Receipt> {
+        if self.len() >= self.max_pending {
+            return None;
+        }
+        let receipt = Receipt {
+            receipt: format!("{:016x}", rand::random::<u64>()),
+            status: ReceiptStatus::Pending,
+            received_at: timestamp_in_sec(),
+            channel_id: None,
+            bundle_id: None,
+            messages: vec![],
+        };
+        self.receipts
+            .insert(receipt.receipt.clone(), receipt.clone());
+        self.pending.push((receipt.receipt.clone(), sensor_data));
+        Some(receipt)
+    }
+
+    ///
+    /// number of readings not yet published, including those of the flush in progress
+    ///
+    pub fn len(&self) -> usize {
+        self.pending.len()
+            + self.in_flight
+            + self
+                .unfinished
+                .iter()
+                .map(|unfinished| unfinished.readings.len())
+                .sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn receipt(&self, id: &str) -> Option<Receipt> {
+        self.receipts.get(id).cloned()
+    }
+
+    ///
+    /// removes the receipts of handled readings older than "receipt_ttl"
+    ///
+    pub fn prune(&mut self) {
+        let now = timestamp_in_sec();
+        let ttl = self.receipt_ttl;
+        self.receipts.retain(|_, receipt| {
+            receipt.status == ReceiptStatus::Pending
+                || now.saturating_sub(receipt.received_at) < ttl
+        });
+    }
+
+    ///
+    /// puts readings which could not be published back in front of the batch, so they are sent with the next flush
+    ///
+    fn requeue(&mut self, readings: Vec<(String, SensorData)>) {
+        let newer = std::mem::replace(&mut self.pending, readings);
+        self.pending.extend(newer);
+    }
+
+    ///
+    /// takes the pending readings for publishing
+    ///
+    fn take_pending(&mut self) -> Vec<(String, SensorData)> {
+        let pending: Vec<(String, SensorData)> = self.pending.drain(..).collect();
+        self.in_flight = pending.len();
+        pending
+    }
+
+    ///
+    /// takes the oldest bundle published partly for completing it
+    ///
+    fn take_unfinished(&mut self) -> Option<UnfinishedBundle> {
+        if self.unfinished.is_empty() {
+            return None;
+        }
+        let unfinished = self.unfinished.remove(0);
+        self.in_flight = unfinished.readings.len();
+        Some(unfinished)
+    }
+
+    ///
+    /// updates the receipts of the readings with the outcome of their bundle. A bundle published partly is appended
+    /// to the unfinished bundles with its remaining parts, so the next flush completes it instead of publishing
+    /// the readings again
+    ///
+    fn finish(
+        &mut self,
+        readings: Vec<(String, SensorData)>,
+        channel_id: &str,
+        published: Result<PublishedBundle, BundleError>,
+    ) {
+        self.in_flight = 0;
+        let (status, bundle_id, messages) = match published {
+            Ok(published) => {
+                info!(messages = published.links.len(), "batch published");
+                (
+                    ReceiptStatus::Published,
+                    published.bundle_id,
+                    published.links,
+                )
+            }
+            Err(BundleError::TooLarge(size)) => {
+                warn!(size, "batch item exceeds the Streams payload size");
+                (ReceiptStatus::Failed, None, vec![])
+            }
+            Err(BundleError::Publish(partial)) if partial.published.links.is_empty() => {
+                error!(error = %partial.error, "could not publish batch to IOTA node, the readings are sent with the next flush");
+                self.requeue(readings);
+                return;
+            }
+            Err(BundleError::Publish(partial)) => {
+                error!(
+                    error = %partial.error,
+                    remaining = partial.remaining.len(),
+                    "could not publish all parts of the batch, the remaining parts are sent with the next flush"
+                );
+                let PartialBundle {
+                    published,
+                    remaining,
+                    ..
+                } = partial;
+                for (id, _) in &readings {
+                    if let Some(receipt) = self.receipts.get_mut(id) {
+                        receipt.channel_id = Some(channel_id.to_string());
+                        receipt.bundle_id = published.bundle_id.clone();
+                        receipt.messages = published.links.clone();
+                    }
+                }
+                self.unfinished.push(UnfinishedBundle {
+                    readings: readings,
+                    published: published,
+                    remaining: remaining,
+                });
+                return;
+            }
+        };
+        for (id, _) in readings {
+            if let Some(receipt) = self.receipts.get_mut(&id) {
+                receipt.status = status;
+                receipt.channel_id = Some(channel_id.to_string());
+                receipt.bundle_id = bundle_id.clone();
+                receipt.messages = messages.clone();
+            }
+        }
+    }
+}
+
+///
+/// Bundle of which only some parts could be published, with the parts still to publish
+///
+#[derive(Debug)]
+struct UnfinishedBundle {
+    readings: Vec<(String, SensorData)>,
+    published: PublishedBundle,
+    remaining: Vec<BundlePart<serde_json::Value>>,
+}
+
+///
+/// Adds the reading to the batch, starting a flush right away once "max_readings" are waiting.
+/// Returns None if the batch is full
+///
+pub fn accept(gateway: &Arc<Gateway>, config: &Config, sensor_data: SensorData) -> Option<Receipt> {
+    let max_readings = config
+        .batching
+        .as_ref()
+        .map_or(1, |batching| batching.max_readings);
+    let (receipt, full) = {
+        let mut batch = gateway.batch.lock().unwrap();
+        let receipt = batch.push(sensor_data)?;
+        (receipt, batch.len() >= max_readings)
+    };
+    if full {
+        let (gateway, config) = (gateway.clone(), config.clone());
+        tokio::spawn(async move { flush(&gateway, &config).await });
+    }
+    Some(receipt)
+}
+
+///
+/// Publishes the buffered readings as one bundle and updates their receipts. Readings which could not be published
+/// stay pending and are sent with the next flush, bundles published partly are completed first.
+/// Flushes wait for each other, so the readings are published in order
+///
+pub async fn flush(gateway: &Arc<Gateway>, config: &Config) {
+    let flushing = gateway.batch.lock().unwrap().flushing.clone();
+    let _flushing = flushing.lock().await;
+
+    loop {
+        let unfinished = gateway.batch.lock().unwrap().take_unfinished();
+        let unfinished = match unfinished {
+            Some(unfinished) => unfinished,
+            None => break,
+        };
+        let span = info_span!("flush", readings = unfinished.readings.len());
+        let completed = complete_bundle(gateway, unfinished).instrument(span).await;
+        if !completed {
+            return;
+        }
+    }
+
+    let pending = gateway.batch.lock().unwrap().take_pending();
+    if pending.is_empty() {
+        return;
+    }
+    let span = info_span!("flush", readings = pending.len());
+    publish_batch(gateway, config, pending)
+        .instrument(span)
+        .await
+}
+
+///
+/// Publishes the remaining parts of a bundle, returning whether the bundle is complete now
+///
+async fn complete_bundle(gateway: &Arc<Gateway>, unfinished: UnfinishedBundle) -> bool {
+    let UnfinishedBundle {
+        readings,
+        published,
+        remaining,
+    } = unfinished;
+    let published = splitter::publish_parts(gateway, None, remaining, published)
+        .await
+        .map_err(BundleError::Publish);
+    let completed = published.is_ok();
+    let channel_id = channel_id(gateway, &published).await;
+    for (_, sensor_data) in &readings {
+        notify(gateway, &channel_id, sensor_data, completed);
+    }
+    gateway
+        .batch
+        .lock()
+        .unwrap()
+        .finish(readings, &channel_id, published);
+    completed
+}
+
+async fn publish_batch(
+    gateway: &Arc<Gateway>,
+    config: &Config,
+    pending: Vec<(String, SensorData)>,
+) {
+    let bundle_data = BundleData {
+        bundle: pending.iter().map(|(_, reading)| reading.clone()).collect(),
+    };
+
+    let published = splitter::publish_bundle(gateway, None, &bundle_data, config).await;
+    let channel_id = channel_id(gateway, &published).await;
+    for sensor_data in &bundle_data.bundle {
+        notify(gateway, &channel_id, sensor_data, published.is_ok());
+    }
+
+    gateway
+        .batch
+        .lock()
+        .unwrap()
+        .finish(pending, &channel_id, published);
+}
+
+async fn channel_id(
+    gateway: &Arc<Gateway>,
+    published: &Result<PublishedBundle, BundleError>,
+) -> String {
+    match published {
+        Ok(published) => published.channel_id.clone(),
+        Err(BundleError::Publish(partial)) if !partial.published.channel_id.is_empty() => {
+            partial.published.channel_id.clone()
+        }
+        Err(_) => publisher::channel_id(gateway, None)
+            .await
+            .unwrap_or_default(),
+    }
+}
+
+///
+/// Flushes the batch every "interval" seconds until the shutdown is requested, expired receipts are removed
+/// on every tick
+///
+pub async fn run(gateway: Arc<Gateway>, config: Config) {
+    let interval = match &config.batching {
+        Some(batching) => Duration::from_secs(batching.interval),
+        None => return,
+    };
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                gateway.batch.lock().unwrap().prune();
+                flush(&gateway, &config).await;
+            }
+            _ = shutdown::requested() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(max_pending: usize, receipt_ttl: u64) -> Batch {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "whitelisted_device_ids": ["DEVICE_ID_1"],
+            "port": 8080,
+            "node": "http://127.0.0.1:14265",
+            "local_pow": false,
+            "batching": {"max_readings": 1, "max_pending": max_pending, "receipt_ttl": receipt_ttl},
+        }))
+        .unwrap();
+        Batch::new(&config)
+    }
+
+    fn reading(value: usize) -> SensorData {
+        serde_json::from_value(serde_json::json!({
+            "iot2tangle": [{"sensor": "Gyroscope", "data": [{"x": value.to_string()}]}],
+            "device": "DEVICE_ID_1",
+            "timestamp": 0,
+        }))
+        .unwrap()
+    }
+
+    ///
+    /// the outcome of a bundle of which the given links were published before the node failed
+    ///
+    fn partly_published(links: &[&str]) -> Result<PublishedBundle, BundleError> {
+        Err(BundleError::Publish(PartialBundle {
+            error: anyhow::anyhow!("node failed"),
+            published: PublishedBundle {
+                channel_id: "CHANNEL".to_string(),
+                links: links.iter().map(|link| link.to_string()).collect(),
+                bundle_id: Some("BUNDLE".to_string()),
+            },
+            remaining: vec![BundlePart {
+                bundle_id: "BUNDLE".to_string(),
+                part: 2,
+                total: 2,
+                bundle: vec![],
+            }],
+        }))
+    }
+
+    fn values(readings: &[(String, SensorData)]) -> Vec<serde_json::Value> {
+        readings
+            .iter()
+            .map(|(_, reading)| reading.iot2tangle[0].data[0]["x"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn readings_beyond_max_pending_are_rejected() {
+        let mut batch = batch(3, 3600);
+        for i in 0..3 {
+            assert!(batch.push(reading(i)).is_some());
+        }
+        assert!(batch.push(reading(3)).is_none());
+        assert_eq!(batch.len(), 3);
+    }
+
+    #[test]
+    fn readings_being_published_count_against_the_limit() {
+        let mut batch = batch(3, 3600);
+        batch.push(reading(0));
+        batch.push(reading(1));
+        let taken = batch.take_pending();
+        assert_eq!(batch.len(), 2);
+
+        batch.push(reading(2));
+        assert!(batch.push(reading(3)).is_none());
+
+        // a batch which could not be published is put back in front of the newer readings
+        batch.finish(taken, "CHANNEL", partly_published(&[]));
+        assert_eq!(batch.len(), 3);
+        assert_eq!(values(&batch.pending), vec!["0", "1", "2"]);
+        assert!(batch.unfinished.is_empty());
+    }
+
+    #[test]
+    fn partly_published_bundles_are_appended() {
+        let mut batch = batch(10, 3600);
+        let first = batch.push(reading(0)).unwrap();
+        let taken = batch.take_pending();
+        batch.finish(taken, "CHANNEL", partly_published(&["LINK_1"]));
+        batch.push(reading(1));
+        let taken = batch.take_pending();
+        batch.finish(taken, "CHANNEL", partly_published(&["LINK_2"]));
+
+        assert_eq!(batch.unfinished.len(), 2);
+        assert_eq!(batch.len(), 2);
+        let receipt = batch.receipt(&first.receipt).unwrap();
+        assert_eq!(receipt.status, ReceiptStatus::Pending);
+        assert_eq!(receipt.messages, vec!["LINK_1"]);
+
+        // the oldest bundle is completed first
+        let unfinished = batch.take_unfinished().unwrap();
+        assert_eq!(values(&unfinished.readings), vec!["0"]);
+        assert_eq!(batch.len(), 2);
+    }
+
+    #[test]
+    fn receipts_get_the_outcome_of_their_bundle() {
+        let mut batch = batch(10, 3600);
+        let published = batch.push(reading(0)).unwrap();
+        let taken = batch.take_pending();
+        batch.finish(
+            taken,
+            "CHANNEL",
+            Ok(PublishedBundle {
+                channel_id: "CHANNEL".to_string(),
+                links: vec!["LINK".to_string()],
+                bundle_id: None,
+            }),
+        );
+        let failed = batch.push(reading(1)).unwrap();
+        let taken = batch.take_pending();
+        batch.finish(taken, "CHANNEL", Err(BundleError::TooLarge(40000)));
+
+        let published = batch.receipt(&published.receipt).unwrap();
+        assert_eq!(published.status, ReceiptStatus::Published);
+        assert_eq!(published.channel_id.as_deref(), Some("CHANNEL"));
+        assert_eq!(published.messages, vec!["LINK"]);
+        assert_eq!(
+            batch.receipt(&failed.receipt).unwrap().status,
+            ReceiptStatus::Failed
+        );
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn prune_keeps_the_receipts_of_pending_readings() {
+        let mut batch = batch(10, 0);
+        let published = batch.push(reading(0)).unwrap();
+        let taken = batch.take_pending();
+        batch.finish(
+            taken,
+            "CHANNEL",
+            Ok(PublishedBundle {
+                channel_id: "CHANNEL".to_string(),
+                links: vec!["LINK".to_string()],
+                bundle_id: None,
+            }),
+        );
+        let pending = batch.push(reading(1)).unwrap();
+
+        batch.prune();
+        assert!(batch.receipt(&published.receipt).is_none());
+        assert!(batch.receipt(&pending.receipt).is_some());
+    }
+}
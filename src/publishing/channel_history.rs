@@ -0,0 +1,145 @@
+use crate::publishing::routing::DEFAULT_CHANNEL;
+use crate::storage;
+use crate::timestamp_in_sec;
+use crate::types::channel_record::{ChannelRecord, OpenReason};
+use crate::types::config::Config;
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+/// message counts are written at most this often, opening and closing channels is written right away
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+///
+/// Every channel opened by the gateway, persisted at "channel_history_path"
+///
+#[derive(Debug)]
+pub struct ChannelHistory {
+    path: PathBuf,
+    records: Vec<ChannelRecord>,
+    last_saved: Instant,
+}
+
+impl ChannelHistory {
+    ///
+    /// loads the stored history, channels left open by a previous run are closed as they are not used anymore
+    ///
+    pub fn load(config: &Config) -> Result<ChannelHistory> {
+        let path = PathBuf::from(&config.channel_history_path);
+        let mut records: Vec<ChannelRecord> = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                format!("Could not parse channel history {}: {}", path.display(), e)
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => {
+                return Err(
+                    format!("Could not read channel history {}: {}", path.display(), e).into(),
+                )
+            }
+        };
+        let now = timestamp_in_sec();
+        for record in records
+            .iter_mut()
+            .filter(|record| record.closed_at.is_none())
+        {
+            record.closed_at = Some(now);
+        }
+        Ok(ChannelHistory {
+            path: path,
+            records: records,
+            last_saved: Instant::now(),
+        })
+    }
+
+    pub fn records(&self) -> Vec<ChannelRecord> {
+        self.records.clone()
+    }
+
+    ///
+    /// the latest record of the channel
+    ///
+    pub fn record(&self, channel_id: &str) -> Option<&ChannelRecord> {
+        self.records
+            .iter()
+            .rev()
+            .find(|record| record.channel_id == channel_id)
+    }
+
+    pub fn opened(&mut self, name: &str, channel_id: &str, reason: OpenReason) {
+        self.records.push(ChannelRecord {
+            channel_id: channel_id.to_string(),
+            name: name.to_string(),
+            reason: reason,
+            opened_at: timestamp_in_sec(),
+            closed_at: None,
+            messages: 0,
+        });
+        self.save();
+    }
+
+    ///
+    /// closes the channel and records the one opened in its place under the same name
+    ///
+    pub fn replaced(&mut self, channel_id: &str, new_channel_id: &str, reason: OpenReason) {
+        let name = match self.open_record(channel_id) {
+            Some(record) => {
+                record.closed_at = Some(timestamp_in_sec());
+                record.name.clone()
+            }
+            None => DEFAULT_CHANNEL.to_string(),
+        };
+        self.opened(&name, new_channel_id, reason);
+    }
+
+    ///
+    /// closes all channels, used when the gateway stops
+    ///
+    pub fn close_all(&mut self) {
+        let now = timestamp_in_sec();
+        for record in self
+            .records
+            .iter_mut()
+            .filter(|record| record.closed_at.is_none())
+        {
+            record.closed_at = Some(now);
+        }
+        self.save();
+    }
+
+    pub fn record_message(&mut self, channel_id: &str) {
+        if let Some(record) = self.open_record(channel_id) {
+            record.messages += 1;
+        }
+        if self.last_saved.elapsed() >= SAVE_INTERVAL {
+            self.save();
+        }
+    }
+
+    fn open_record(&mut self, channel_id: &str) -> Option<&mut ChannelRecord> {
+        self.records
+            .iter_mut()
+            .rev()
+            .find(|record| record.channel_id == channel_id && record.closed_at.is_none())
+    }
+
+    ///
+    /// writes the history atomically, failures are only logged as the history must not stop publishing
+    ///
+    fn save(&mut self) {
+        self.last_saved = Instant::now();
+        let write = || -> Result<()> {
+            let data = serde_json::to_vec(&self.records)?;
+            storage::write_atomic(&self.path, &data, false)?;
+            Ok(())
+        };
+        if let Err(e) = write() {
+            warn!(path = %self.path.display(), error = %e, "could not store channel history");
+        }
+    }
+}
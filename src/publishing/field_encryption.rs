@@ -0,0 +1,420 @@
+use crate::device_auth::keystore::calculate_hash;
+use crate::types::config::Config;
+use crate::types::encrypted_value::EncryptedValue;
+use crate::types::senml::{self, SenmlRecord};
+use crate::types::sensor_data::SensorData;
+
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce, Tag};
+use rand::RngCore;
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::fs;
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+/// algorithm of the encrypted values
+pub const ALG: &str = "A256GCM";
+/// key of the entry replacing the data of an encrypted sensor
+pub const ENCRYPTED_SENSOR: &str = "encrypted";
+
+pub type Key = [u8; 32];
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+#[derive(Debug)]
+struct Rule {
+    tenant: String,
+    key: Key,
+    devices: Vec<String>,
+    sensors: Vec<String>,
+    fields: Vec<String>,
+}
+
+///
+/// Encryption policy of the "field_encryption" rules, the keys are read from their files once on startup
+///
+#[derive(Debug, Default)]
+pub struct FieldEncryption {
+    rules: Vec<Rule>,
+}
+
+impl FieldEncryption {
+    pub fn from_config(config: &Config) -> Result<FieldEncryption> {
+        let mut rules = vec![];
+        for rule in &config.field_encryption {
+            rules.push(Rule {
+                tenant: rule.tenant.clone(),
+                key: read_key(&rule.key_file)?,
+                // readings carry the hash of the device
+                devices: rule.devices.iter().cloned().map(calculate_hash).collect(),
+                sensors: rule.sensors.clone(),
+                fields: rule.fields.clone(),
+            });
+        }
+        Ok(FieldEncryption { rules: rules })
+    }
+
+    ///
+    /// encrypts the sensors and fields selected by the rules matching the device of the reading,
+    /// the device has to be hashed already
+    ///
+    pub fn apply(&self, sensor_data: &mut SensorData) {
+        for rule in &self.rules {
+            if !rule.devices.is_empty() && !rule.devices.contains(&sensor_data.device) {
+                continue;
+            }
+            for sensor in &mut sensor_data.iot2tangle {
+                if rule.sensors.contains(&sensor.sensor) {
+                    if encrypted_sensor(&sensor.data).is_some() {
+                        continue;
+                    }
+                    let plaintext = Value::Array(sensor.data.clone());
+                    let encrypted = seal(rule, &sensor.sensor, "", &plaintext);
+                    let mut entry = serde_json::Map::new();
+                    entry.insert(ENCRYPTED_SENSOR.to_string(), encrypted);
+                    sensor.data = vec![Value::Object(entry)];
+                    continue;
+                }
+                for entry in &mut sensor.data {
+                    let entry = match entry {
+                        Value::Object(entry) => entry,
+                        _ => continue,
+                    };
+                    for field in &rule.fields {
+                        if let Some(value) = entry.get_mut(field) {
+                            if !is_encrypted(value) {
+                                *value = seal(rule, &sensor.sensor, field, value);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+///
+/// reads a key file holding 32 bytes encoded as base64
+///
+pub fn read_key(path: &str) -> Result<Key> {
+    let encoded =
+        fs::read_to_string(path).map_err(|e| format!("Could not read key {}: {}", path, e))?;
+    let decoded = base64::decode(encoded.trim())
+        .map_err(|e| format!("Could not decode key {}: {}", path, e))?;
+    if decoded.len() != 32 {
+        return Err(format!("Key {} must be 32 bytes, found {}", path, decoded.len()).into());
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&decoded);
+    Ok(key)
+}
+
+fn aad(tenant: &str, sensor: &str, field: &str) -> Vec<u8> {
+    format!("{}/{}/{}", tenant, sensor, field).into_bytes()
+}
+
+fn seal(rule: &Rule, sensor: &str, field: &str, value: &Value) -> Value {
+    let plaintext = serde_json::to_vec(value).expect("value serializes to JSON");
+    let mut nonce = [0u8; NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut ciphertext = plaintext;
+    let tag = Aes256Gcm::new(&rule.key.into())
+        .encrypt_in_place_detached(
+            Nonce::from_slice(&nonce),
+            &aad(&rule.tenant, sensor, field),
+            &mut ciphertext,
+        )
+        .expect("value fits into a single AES-GCM message");
+
+    let encrypted = EncryptedValue {
+        alg: ALG.to_string(),
+        tenant: rule.tenant.clone(),
+        nonce: base64::encode(&nonce),
+        tag: base64::encode(&tag),
+        ciphertext: base64::encode(&ciphertext),
+    };
+    serde_json::to_value(encrypted).expect("encrypted value serializes to JSON")
+}
+
+fn encrypted_value(value: &Value) -> Option<EncryptedValue> {
+    match value {
+        Value::Object(entry) if entry.get("alg") == Some(&Value::from(ALG)) => {
+            serde_json::from_value(value.clone()).ok()
+        }
+        _ => None,
+    }
+}
+
+fn is_encrypted(value: &Value) -> bool {
+    encrypted_value(value).is_some()
+}
+
+///
+/// the encrypted value of a sensor encrypted as a whole, stored as its only entry under "encrypted"
+///
+fn encrypted_sensor(data: &[Value]) -> Option<EncryptedValue> {
+    match data {
+        [Value::Object(entry)] => entry.get(ENCRYPTED_SENSOR).and_then(encrypted_value),
+        _ => None,
+    }
+}
+
+fn open(encrypted: &EncryptedValue, key: &Key, sensor: &str, field: &str) -> Result<Value> {
+    let nonce = base64::decode(&encrypted.nonce)?;
+    let tag = base64::decode(&encrypted.tag)?;
+    if nonce.len() != NONCE_SIZE || tag.len() != TAG_SIZE {
+        return Err(format!(
+            "Could not decrypt {}/{} for tenant {}: invalid nonce or tag",
+            sensor, field, encrypted.tenant
+        )
+        .into());
+    }
+    let mut plaintext = base64::decode(&encrypted.ciphertext)?;
+    let valid = Aes256Gcm::new(&(*key).into())
+        .decrypt_in_place_detached(
+            Nonce::from_slice(&nonce),
+            &aad(&encrypted.tenant, sensor, field),
+            &mut plaintext,
+            Tag::from_slice(&tag),
+        )
+        .is_ok();
+    if !valid {
+        return Err(format!(
+            "Could not decrypt {}/{} for tenant {}: wrong key or modified data",
+            sensor, field, encrypted.tenant
+        )
+        .into());
+    }
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+///
+/// Decrypts the values of the reading encrypted for the tenants of the provided keys, values of other tenants
+/// are left encrypted. Returns the number of decrypted values
+///
+pub fn decrypt(sensor_data: &mut SensorData, keys: &HashMap<String, Key>) -> Result<usize> {
+    let mut decrypted = 0;
+    for sensor in &mut sensor_data.iot2tangle {
+        if let Some(encrypted) = encrypted_sensor(&sensor.data) {
+            if let Some(key) = keys.get(&encrypted.tenant) {
+                match open(&encrypted, key, &sensor.sensor, "")? {
+                    Value::Array(data) => sensor.data = data,
+                    _ => return Err(format!("Sensor {} is not a list", sensor.sensor).into()),
+                }
+                decrypted += 1;
+            }
+        }
+        for entry in &mut sensor.data {
+            let entry = match entry {
+                Value::Object(entry) => entry,
+                _ => continue,
+            };
+            for (field, value) in entry.iter_mut() {
+                let encrypted = match encrypted_value(value) {
+                    Some(encrypted) => encrypted,
+                    None => continue,
+                };
+                if let Some(key) = keys.get(&encrypted.tenant) {
+                    *value = open(&encrypted, key, &sensor.sensor, field)?;
+                    decrypted += 1;
+                }
+            }
+        }
+    }
+    Ok(decrypted)
+}
+
+///
+/// Decrypts the records of a SenML pack published with "publish_senml", where the encrypted values are stored
+/// as JSON in "vs". The records of an encrypted sensor are put in place of its record. Returns the number
+/// of decrypted values
+///
+pub fn decrypt_pack(pack: &mut Vec<SenmlRecord>, keys: &HashMap<String, Key>) -> Result<usize> {
+    let mut decrypted = 0;
+    let mut records = Vec::with_capacity(pack.len());
+    for mut record in pack.iter().cloned() {
+        let encrypted = record
+            .vs
+            .as_deref()
+            .and_then(|vs| serde_json::from_str::<Value>(vs).ok())
+            .and_then(|value| encrypted_value(&value));
+        let (encrypted, key) = match encrypted {
+            Some(encrypted) => match keys.get(&encrypted.tenant) {
+                Some(key) => (encrypted, key),
+                None => {
+                    records.push(record);
+                    continue;
+                }
+            },
+            None => {
+                records.push(record);
+                continue;
+            }
+        };
+        let sensor = record.n.clone().unwrap_or_default();
+        let field = record.u.clone().unwrap_or_default();
+        if field == ENCRYPTED_SENSOR {
+            let data = match open(&encrypted, key, &sensor, "")? {
+                Value::Array(data) => data,
+                _ => return Err(format!("Sensor {} is not a list", sensor).into()),
+            };
+            // the records of the sensor keep the base values of the encrypted one
+            let mut expanded = senml::sensor_records(&sensor, &data);
+            match expanded.first_mut() {
+                Some(first) => {
+                    first.bn = record.bn;
+                    first.bt = record.bt;
+                }
+                None if record.bn.is_some() || record.bt.is_some() => {
+                    expanded.push(SenmlRecord {
+                        bn: record.bn,
+                        bt: record.bt,
+                        ..Default::default()
+                    });
+                }
+                None => {}
+            }
+            records.extend(expanded);
+        } else {
+            let value = open(&encrypted, key, &sensor, &field)?;
+            record.vs = None;
+            senml::set_value(&mut record, &value);
+            records.push(record);
+        }
+        decrypted += 1;
+    }
+    *pack = records;
+    Ok(decrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACME: Key = [7u8; 32];
+    const OTHER: Key = [9u8; 32];
+
+    fn policy() -> FieldEncryption {
+        FieldEncryption {
+            rules: vec![
+                Rule {
+                    tenant: "acme".to_string(),
+                    key: ACME,
+                    devices: vec![],
+                    sensors: vec!["Gyroscope".to_string()],
+                    fields: vec!["Temp".to_string()],
+                },
+                Rule {
+                    tenant: "other".to_string(),
+                    key: OTHER,
+                    devices: vec![],
+                    sensors: vec![],
+                    fields: vec!["Hum".to_string()],
+                },
+            ],
+        }
+    }
+
+    fn reading() -> SensorData {
+        serde_json::from_value(serde_json::json!({
+            "iot2tangle": [
+                {"sensor": "Gyroscope", "data": [{"x": "4514"}, {"y": 28}, {"z": true}]},
+                {"sensor": "Environment", "data": [{"Temp": 21.5, "Hum": "40", "Press": "1013"}]}
+            ],
+            "device": "DEVICE_ID_1",
+            "timestamp": 1600000000
+        }))
+        .expect("reading")
+    }
+
+    fn keys(keys: &[(&str, Key)]) -> HashMap<String, Key> {
+        keys.iter()
+            .map(|(tenant, key)| (tenant.to_string(), *key))
+            .collect()
+    }
+
+    #[test]
+    fn encrypted_reading_decrypts_to_the_original() {
+        let mut sensor_data = reading();
+        policy().apply(&mut sensor_data);
+        let encrypted = serde_json::to_value(&sensor_data).unwrap();
+        assert!(!encrypted.to_string().contains("4514"));
+        assert!(!encrypted.to_string().contains("21.5"));
+        assert_eq!(encrypted["iot2tangle"][1]["data"][0]["Press"], "1013");
+
+        // applying the policy again does not encrypt twice
+        policy().apply(&mut sensor_data);
+        assert_eq!(serde_json::to_value(&sensor_data).unwrap(), encrypted);
+
+        let decrypted = decrypt(&mut sensor_data, &keys(&[("acme", ACME), ("other", OTHER)]));
+        assert_eq!(decrypted.unwrap(), 3);
+        assert_eq!(
+            serde_json::to_value(&sensor_data).unwrap(),
+            serde_json::to_value(reading()).unwrap()
+        );
+    }
+
+    #[test]
+    fn values_of_other_tenants_stay_encrypted() {
+        let mut sensor_data = reading();
+        policy().apply(&mut sensor_data);
+        assert_eq!(
+            decrypt(&mut sensor_data, &keys(&[("acme", ACME)])).unwrap(),
+            2
+        );
+        let value = serde_json::to_value(&sensor_data).unwrap();
+        assert_eq!(value["iot2tangle"][0]["data"][0]["x"], "4514");
+        assert_eq!(value["iot2tangle"][1]["data"][0]["Temp"], 21.5);
+        assert!(is_encrypted(&value["iot2tangle"][1]["data"][0]["Hum"]));
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let mut sensor_data = reading();
+        policy().apply(&mut sensor_data);
+        assert!(decrypt(&mut sensor_data, &keys(&[("acme", OTHER)])).is_err());
+    }
+
+    #[test]
+    fn moved_value_is_rejected() {
+        let mut sensor_data = reading();
+        policy().apply(&mut sensor_data);
+        // the associated data binds the value to its sensor and field
+        let mut entry = sensor_data.iot2tangle[1].data[0].clone();
+        let temp = entry["Temp"].clone();
+        entry["Press"] = temp;
+        sensor_data.iot2tangle[1].data[0] = entry;
+        assert!(decrypt(&mut sensor_data, &keys(&[("acme", ACME), ("other", OTHER)])).is_err());
+    }
+
+    #[test]
+    fn published_senml_pack_decrypts_to_the_original() {
+        let mut sensor_data = reading();
+        sensor_data.device = calculate_hash(sensor_data.device);
+        let original = senml::sensor_data_to_pack(&sensor_data);
+        policy().apply(&mut sensor_data);
+        let mut pack = senml::sensor_data_to_pack(&sensor_data);
+        assert_eq!(pack.len(), 4);
+
+        let decrypted = decrypt_pack(&mut pack, &keys(&[("acme", ACME), ("other", OTHER)]));
+        assert_eq!(decrypted.unwrap(), 3);
+        assert_eq!(
+            serde_json::to_value(&pack).unwrap(),
+            serde_json::to_value(&original).unwrap()
+        );
+    }
+
+    #[test]
+    fn senml_pack_with_wrong_key_is_left_unchanged() {
+        let mut sensor_data = reading();
+        policy().apply(&mut sensor_data);
+        let mut pack = senml::sensor_data_to_pack(&sensor_data);
+        let published = serde_json::to_value(&pack).unwrap();
+        assert!(decrypt_pack(&mut pack, &keys(&[("acme", OTHER)])).is_err());
+        assert_eq!(serde_json::to_value(&pack).unwrap(), published);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::types::anchor::{InclusionProof, ProofStep, Side};
+use crate::types::sensor_data::SensorData;
+
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+///
+/// SHA3-256 of 0x00 followed by the JSON of the reading, as hex
+///
+pub fn leaf_hash(reading: &SensorData) -> String {
+    let json = serde_json::to_vec(reading).expect("reading serializes to JSON");
+    let mut hasher = Sha3::sha3_256();
+    hasher.input(&[LEAF_PREFIX]);
+    hasher.input(&json);
+    hasher.result_str()
+}
+
+///
+/// SHA3-256 of 0x01 followed by the hex strings of the left and right child, as hex
+///
+pub fn node_hash(left: &str, right: &str) -> String {
+    let mut hasher = Sha3::sha3_256();
+    hasher.input(&[NODE_PREFIX]);
+    hasher.input_str(left);
+    hasher.input_str(right);
+    hasher.result_str()
+}
+
+///
+/// hashes of the next level, the last node of a level with an odd number of nodes is moved up unchanged
+///
+fn parents(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [single] => single.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+///
+/// Merkle root of the leaves, None for an empty list
+///
+pub fn root(leaves: &[String]) -> Option<String> {
+    if leaves.is_empty() {
+        return None;
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = parents(&level);
+    }
+    level.pop()
+}
+
+///
+/// sibling hashes from the leaf at index up to the root
+///
+pub fn path(leaves: &[String], mut index: usize) -> Vec<ProofStep> {
+    let mut steps = vec![];
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            steps.push(ProofStep {
+                hash: level[sibling].clone(),
+                side: if sibling < index {
+                    Side::Left
+                } else {
+                    Side::Right
+                },
+            });
+        }
+        level = parents(&level);
+        index /= 2;
+    }
+    steps
+}
+
+///
+/// Verifies that the reading of the proof is included in its Merkle root.
+/// The root still has to be compared with the message published at the message_link of the proof
+///
+pub fn verify(proof: &InclusionProof) -> bool {
+    let leaf = leaf_hash(&proof.reading);
+    if leaf != proof.leaf {
+        return false;
+    }
+    let computed = proof.path.iter().fold(leaf, |hash, step| match step.side {
+        Side::Left => node_hash(&step.hash, &hash),
+        Side::Right => node_hash(&hash, &step.hash),
+    });
+    computed == proof.merkle_root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(value: u64) -> SensorData {
+        serde_json::from_value(serde_json::json!({
+            "iot2tangle": [{"sensor": "Env", "data": [{"Temp": value}]}],
+            "device": "DEVICE_ID_1",
+            "timestamp": 1600000000 + value,
+        }))
+        .expect("reading")
+    }
+
+    fn proof(readings: &[SensorData], index: usize) -> InclusionProof {
+        let leaves: Vec<String> = readings.iter().map(leaf_hash).collect();
+        InclusionProof {
+            reading_id: format!("{}", index),
+            reading: readings[index].clone(),
+            leaf: leaves[index].clone(),
+            path: path(&leaves, index),
+            merkle_root: root(&leaves).expect("root"),
+            channel_id: String::new(),
+            message_link: String::new(),
+            anchored_at: 0,
+        }
+    }
+
+    #[test]
+    fn empty_list_has_no_root() {
+        assert_eq!(root(&[]), None);
+    }
+
+    #[test]
+    fn single_leaf_is_the_root() {
+        let leaf = leaf_hash(&reading(0));
+        assert_eq!(root(std::slice::from_ref(&leaf)), Some(leaf));
+        assert!(path(&[leaf_hash(&reading(0))], 0).is_empty());
+        assert!(verify(&proof(&[reading(0)], 0)));
+    }
+
+    #[test]
+    fn two_leaves_are_hashed_in_order() {
+        let leaves = vec![leaf_hash(&reading(0)), leaf_hash(&reading(1))];
+        assert_eq!(root(&leaves), Some(node_hash(&leaves[0], &leaves[1])));
+        assert_ne!(root(&leaves), Some(node_hash(&leaves[1], &leaves[0])));
+        assert_eq!(path(&leaves, 0)[0].side, Side::Right);
+        assert_eq!(path(&leaves, 1)[0].side, Side::Left);
+    }
+
+    #[test]
+    fn odd_node_is_moved_up_unchanged() {
+        let leaves: Vec<String> = (0..3).map(|i| leaf_hash(&reading(i))).collect();
+        let expected = node_hash(&node_hash(&leaves[0], &leaves[1]), &leaves[2]);
+        assert_eq!(root(&leaves), Some(expected));
+        // the promoted leaf has no sibling on the first level
+        let steps = path(&leaves, 2);
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].hash, node_hash(&leaves[0], &leaves[1]));
+        assert_eq!(steps[0].side, Side::Left);
+    }
+
+    #[test]
+    fn every_reading_verifies() {
+        for count in &[1, 2, 3, 5] {
+            let readings: Vec<SensorData> = (0..*count).map(reading).collect();
+            for index in 0..readings.len() {
+                assert!(
+                    verify(&proof(&readings, index)),
+                    "leaf {} of {}",
+                    index,
+                    count
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn five_leaves_promote_the_last_node_twice() {
+        let leaves: Vec<String> = (0..5).map(|i| leaf_hash(&reading(i))).collect();
+        assert_eq!(path(&leaves, 4).len(), 1);
+        assert_eq!(path(&leaves, 0).len(), 3);
+    }
+
+    #[test]
+    fn tampered_reading_does_not_verify() {
+        let readings: Vec<SensorData> = (0..5).map(reading).collect();
+        let mut tampered = proof(&readings, 2);
+        tampered.reading = reading(7);
+        assert!(!verify(&tampered));
+        // a leaf recomputed for the tampered reading does not lead to the root
+        tampered.leaf = leaf_hash(&tampered.reading);
+        assert!(!verify(&tampered));
+    }
+
+    #[test]
+    fn tampered_path_does_not_verify() {
+        let readings: Vec<SensorData> = (0..5).map(reading).collect();
+
+        let mut tampered = proof(&readings, 1);
+        tampered.path[1].hash = leaf_hash(&reading(9));
+        assert!(!verify(&tampered));
+
+        let mut swapped = proof(&readings, 1);
+        swapped.path[0].side = Side::Right;
+        assert!(!verify(&swapped));
+
+        let mut shortened = proof(&readings, 1);
+        shortened.path.pop();
+        assert!(!verify(&shortened));
+
+        let mut other_root = proof(&readings, 1);
+        other_root.merkle_root = proof(&readings[..4], 1).merkle_root;
+        assert!(!verify(&other_root));
+    }
+}
@@ -0,0 +1,41 @@
+///
+/// IOTA nodes used for publishing and their health
+pub mod node_pool;
+///
+/// publishing of messages off the async runtime, with retries of transient failures
+pub mod publisher;
+///
+/// retries with backoff and the circuit breaker guarding the IOTA node
+pub mod retry;
+
+///
+/// splitting of bundles too large for a single message
+pub mod splitter;
+
+///
+/// buffering of single readings which are published together as a bundle
+pub mod batcher;
+
+///
+/// Merkle trees over readings and the verification of inclusion proofs
+pub mod merkle;
+
+///
+/// storing readings locally and publishing only the Merkle root of each batch
+pub mod anchoring;
+
+///
+/// encryption of selected sensor values with the keys of tenants
+pub mod field_encryption;
+
+///
+/// routing of sensors to named channels
+pub mod routing;
+
+///
+/// history of the channels opened by the gateway
+pub mod channel_history;
+
+///
+/// replacing channels by new ones, on request or every "channel_rotation" seconds
+pub mod rotation;
@@ -0,0 +1,341 @@
+use crate::monitoring::metrics;
+use crate::types::config::Config;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use gateway_core::gateway::publisher::Channel;
+use hyper::Uri;
+use rand::Rng;
+use serde_derive::Serialize;
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+/// time to wait for a node to accept a connection
+const NODE_TIMEOUT: Duration = Duration::from_secs(3);
+static SEED_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ9";
+
+#[derive(Serialize, Debug, Clone)]
+pub struct NodeStatus {
+    pub url: String,
+    pub priority: u32,
+    pub healthy: bool,
+}
+
+///
+/// The configured nodes ordered by priority, and the node the current channel is opened on
+///
+#[derive(Serialize, Debug)]
+pub struct NodePool {
+    pub active: String,
+    pub nodes: Vec<NodeStatus>,
+    #[serde(skip)]
+    pub local_pow: bool,
+    #[serde(skip)]
+    pub dry_run: bool,
+}
+
+impl NodePool {
+    ///
+    /// creates the pool from the "nodes" of the configuration, falling back to "node" if none are configured
+    ///
+    pub fn new(config: &Config) -> NodePool {
+        let mut nodes: Vec<NodeStatus> = config
+            .nodes
+            .iter()
+            .map(|node| NodeStatus {
+                url: node.url.clone(),
+                priority: node.priority,
+                healthy: true,
+            })
+            .collect();
+        if nodes.is_empty() {
+            nodes.push(NodeStatus {
+                url: config.node.clone(),
+                priority: 0,
+                healthy: true,
+            });
+        }
+        nodes.sort_by_key(|node| node.priority);
+
+        NodePool {
+            active: nodes[0].url.clone(),
+            nodes: nodes,
+            local_pow: config.local_pow,
+            dry_run: config.dry_run,
+        }
+    }
+
+    ///
+    /// nodes in the order they should be tried, healthy nodes by priority followed by the unhealthy ones
+    ///
+    pub fn candidates(&self) -> Vec<String> {
+        let healthy = self.nodes.iter().filter(|node| node.healthy);
+        let unhealthy = self.nodes.iter().filter(|node| !node.healthy);
+        healthy
+            .chain(unhealthy)
+            .map(|node| node.url.clone())
+            .collect()
+    }
+
+    pub fn set_health(&mut self, url: &str, healthy: bool) {
+        for node in self.nodes.iter_mut().filter(|node| node.url == url) {
+            node.healthy = healthy;
+        }
+    }
+}
+
+///
+/// Opens a channel with the provided seed on the first node that accepts it, nodes refusing it are marked unhealthy.
+/// Returns the channel, its id and the node it was opened on, which becomes the active node of the pool.
+/// In dry-run mode the channel is created without contacting any node and gets a random id
+///
+pub fn open_channel(
+    pool: &Arc<Mutex<NodePool>>,
+    seed: &str,
+) -> anyhow::Result<(Channel, String, String)> {
+    let (candidates, local_pow, dry_run) = {
+        let pool = pool.lock().unwrap();
+        (pool.candidates(), pool.local_pow, pool.dry_run)
+    };
+
+    if dry_run {
+        // the channel is never opened, nothing is sent to the node
+        let node = pool.lock().unwrap().active.clone();
+        let channel = Channel::new(node.clone(), local_pow, Some(seed.to_string()));
+        let channel_id = format!("dry-run:{:016x}", rand::random::<u64>());
+        return Ok((channel, channel_id, node));
+    }
+    open_on(pool, seed, &candidates, local_pow)
+}
+
+///
+/// Opens a channel with the provided seed on the healthy nodes other than the failed one, used to move
+/// a channel away from a node that keeps failing. Returns the channel, its id and the node it was opened on
+///
+pub fn open_channel_elsewhere(
+    pool: &Arc<Mutex<NodePool>>,
+    seed: &str,
+    failed: &str,
+) -> anyhow::Result<(Channel, String, String)> {
+    let (candidates, local_pow) = {
+        let pool = pool.lock().unwrap();
+        let healthy: Vec<String> = pool
+            .nodes
+            .iter()
+            .filter(|node| node.healthy && node.url != failed)
+            .map(|node| node.url.clone())
+            .collect();
+        (healthy, pool.local_pow)
+    };
+    open_on(pool, seed, &candidates, local_pow)
+}
+
+fn open_on(
+    pool: &Arc<Mutex<NodePool>>,
+    seed: &str,
+    candidates: &[String],
+    local_pow: bool,
+) -> anyhow::Result<(Channel, String, String)> {
+    for node in candidates {
+        let mut channel = Channel::new(node.clone(), local_pow, Some(seed.to_string()));
+        match channel.open() {
+            Ok((addr, msg_id)) => {
+                let mut pool = pool.lock().unwrap();
+                pool.set_health(node, true);
+                pool.active = node.clone();
+                info!(node = %node, "channel opened");
+                return Ok((channel, format!("{}:{}", addr, msg_id), node.clone()));
+            }
+            Err(e) => {
+                metrics::NODE_ERRORS.inc();
+                warn!(node = %node, error = %e, "could not open channel");
+                pool.lock().unwrap().set_health(node, false);
+            }
+        }
+    }
+    Err(anyhow!("Could not connect to any IOTA Node"))
+}
+
+///
+/// generates a random seed for a new channel
+///
+pub fn generate_seed() -> String {
+    let mut rng = rand::thread_rng();
+    (0..81)
+        .map(|_| SEED_ALPHABET[rng.gen_range(0, SEED_ALPHABET.len())] as char)
+        .collect()
+}
+
+///
+/// Checks the reachability of all nodes in the pool in the provided interval
+///
+pub async fn probe(pool: Arc<Mutex<NodePool>>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let urls: Vec<String> = pool
+            .lock()
+            .unwrap()
+            .nodes
+            .iter()
+            .map(|node| node.url.clone())
+            .collect();
+        for url in urls {
+            let healthy = node_reachable(&url).await;
+            if !healthy {
+                warn!(node = %url, "node unreachable");
+            }
+            pool.lock().unwrap().set_health(&url, healthy);
+        }
+    }
+}
+
+///
+/// checks if a TCP connection to the node can be established
+///
+pub async fn node_reachable(node: &str) -> bool {
+    let uri = match node.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return false,
+    };
+    let host = match uri.host() {
+        Some(host) => host.to_string(),
+        None => return false,
+    };
+    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("http") => 80,
+        _ => 443,
+    });
+    match tokio::time::timeout(NODE_TIMEOUT, TcpStream::connect((host.as_str(), port))).await {
+        Ok(Ok(_)) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn pool(nodes: serde_json::Value, dry_run: bool) -> NodePool {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "whitelisted_device_ids": ["DEVICE_ID_1"],
+            "port": 8080,
+            "node": "http://fallback:14265",
+            "nodes": nodes,
+            "local_pow": false,
+            "dry_run": dry_run,
+        }))
+        .unwrap();
+        NodePool::new(&config)
+    }
+
+    ///
+    /// a node accepting connections without answering them
+    ///
+    async fn stub_node() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        (listener, url)
+    }
+
+    ///
+    /// the url of a local port nothing listens on
+    ///
+    async fn closed_node() -> String {
+        let (listener, url) = stub_node().await;
+        drop(listener);
+        url
+    }
+
+    #[test]
+    fn falls_back_to_node_without_nodes() {
+        let pool = pool(serde_json::json!([]), false);
+        assert_eq!(pool.active, "http://fallback:14265");
+        assert_eq!(pool.candidates(), vec!["http://fallback:14265"]);
+    }
+
+    #[test]
+    fn candidates_are_healthy_nodes_by_priority_then_unhealthy_ones() {
+        let mut pool = pool(
+            serde_json::json!([
+                {"url": "http://c", "priority": 2},
+                {"url": "http://a", "priority": 0},
+                {"url": "http://b", "priority": 1},
+            ]),
+            false,
+        );
+        assert_eq!(pool.active, "http://a");
+        assert_eq!(pool.candidates(), vec!["http://a", "http://b", "http://c"]);
+
+        pool.set_health("http://a", false);
+        assert_eq!(pool.candidates(), vec!["http://b", "http://c", "http://a"]);
+
+        pool.set_health("http://a", true);
+        assert_eq!(pool.candidates(), vec!["http://a", "http://b", "http://c"]);
+    }
+
+    #[test]
+    fn dry_run_opens_channel_on_active_node_without_contacting_it() {
+        let pool = Arc::new(Mutex::new(pool(
+            serde_json::json!([{"url": "http://127.0.0.1:1"}]),
+            true,
+        )));
+        let seed = generate_seed();
+        let (_, channel_id, node) = open_channel(&pool, &seed).unwrap();
+        assert!(channel_id.starts_with("dry-run:"));
+        assert_eq!(node, "http://127.0.0.1:1");
+        assert!(pool.lock().unwrap().nodes[0].healthy);
+
+        // the id does not reveal anything of the seed
+        let (_, reopened, _) = open_channel(&pool, &seed).unwrap();
+        assert_ne!(reopened, channel_id);
+    }
+
+    #[test]
+    fn seeds_are_random_trytes() {
+        let seed = generate_seed();
+        assert_eq!(seed.len(), 81);
+        assert!(seed.bytes().all(|b| SEED_ALPHABET.contains(&b)));
+        assert_ne!(seed, generate_seed());
+    }
+
+    #[tokio::test]
+    async fn stub_node_is_reachable() {
+        let (_listener, url) = stub_node().await;
+        assert!(node_reachable(&url).await);
+    }
+
+    #[tokio::test]
+    async fn closed_port_and_invalid_url_are_unreachable() {
+        assert!(!node_reachable(&closed_node().await).await);
+        assert!(!node_reachable("not a url").await);
+    }
+
+    #[tokio::test]
+    async fn probe_marks_unreachable_nodes_unhealthy() {
+        let (_listener, reachable) = stub_node().await;
+        let unreachable = closed_node().await;
+        let pool = Arc::new(Mutex::new(pool(
+            serde_json::json!([
+                {"url": unreachable, "priority": 0},
+                {"url": reachable, "priority": 1},
+            ]),
+            false,
+        )));
+
+        // the first tick probes right away, the next one is not reached before the timeout
+        let _ = tokio::time::timeout(
+            Duration::from_millis(500),
+            probe(pool.clone(), Duration::from_secs(60)),
+        )
+        .await;
+
+        let pool = pool.lock().unwrap();
+        assert!(!pool.nodes[0].healthy);
+        assert!(pool.nodes[1].healthy);
+        assert_eq!(pool.candidates(), vec![reachable, unreachable]);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::monitoring::metrics;
+use crate::publishing::node_pool;
+use crate::publishing::retry::{self, CircuitOpen};
+use crate::timestamp_in_sec;
+use crate::types::channel_record::OpenReason;
+use crate::types::channel_state::PublishTarget;
+use crate::types::gateway::Gateway;
+
+use std::mem;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::task;
+use tracing::{info, info_span, warn, Instrument};
+
+///
+/// Message published on a channel
+///
+#[derive(Debug, Clone)]
+pub struct Published {
+    pub channel_id: String,
+    pub link: String,
+}
+
+///
+/// Publishes the payload as signed message on the routed channel, None selects the default channel.
+/// Every attempt runs on a blocking thread which holds the channel state only for the attempt, failed attempts with
+/// transient node or network errors are retried with backoff while the channel state is released. If the node keeps
+/// failing it is marked unhealthy and the channel moved to the next healthy node of the pool. The channel can not be
+/// reopened there with the same seed, that would announce it again and reset the sequence state of the author,
+/// so a new channel is opened in its place and recorded in the history like a switch. The error is returned once
+/// no healthy node is left. Fails with CircuitOpen while the circuit breaker is open.
+/// In dry-run mode the payload is only logged
+///
+pub async fn write_signed<T: Serialize>(
+    gateway: &Arc<Gateway>,
+    route: Option<&str>,
+    payload: &T,
+) -> anyhow::Result<Published> {
+    let payload = serde_json::to_value(payload)?;
+    let route = route.map(|route| route.to_string());
+    publish(gateway.clone(), route, payload)
+        .instrument(info_span!("publish"))
+        .await
+}
+
+async fn publish(
+    gateway: Arc<Gateway>,
+    route: Option<String>,
+    payload: serde_json::Value,
+) -> anyhow::Result<Published> {
+    if gateway.dry_run {
+        let channel_id = channel_id(&gateway, route.as_deref()).await?;
+        info!(payload = %payload, "dry run, message not published");
+        *gateway.last_published.lock().unwrap() = Some(timestamp_in_sec());
+        return Ok(Published {
+            channel_id: channel_id,
+            link: String::from("dry-run"),
+        });
+    }
+    if !gateway.breaker.lock().unwrap().allow() {
+        return Err(anyhow::Error::msg(CircuitOpen));
+    }
+
+    // every move marks a node unhealthy, so a publish visits each node at most once unless the probe revives it
+    let moves = gateway.nodes.lock().unwrap().nodes.len();
+    let mut result = attempt(&gateway, &route, &payload).await;
+    for _ in 1..moves {
+        let (node, e) = match &result {
+            Err((node, e)) if retry::is_transient(e) => (node.clone(), e),
+            _ => break,
+        };
+        warn!(node = %node, error = %e, "node keeps failing, moving the channel to another node");
+        gateway.nodes.lock().unwrap().set_health(&node, false);
+        match move_channel(&gateway, route.as_deref(), &node).await {
+            Ok(channel_id) => {
+                info!(channel_id = %channel_id, "channel moved to another node");
+                result = attempt(&gateway, &route, &payload).await;
+            }
+            Err(e) => {
+                warn!(error = %e, "no other node accepted the channel");
+                break;
+            }
+        }
+    }
+
+    match result {
+        Ok((node, channel_id, link)) => {
+            gateway.history.lock().unwrap().record_message(&channel_id);
+            gateway.nodes.lock().unwrap().set_health(&node, true);
+            gateway.breaker.lock().unwrap().record_success();
+            *gateway.last_published.lock().unwrap() = Some(timestamp_in_sec());
+            Ok(Published {
+                channel_id: channel_id,
+                link: link,
+            })
+        }
+        Err((node, e)) => {
+            if retry::is_transient(&e) {
+                gateway.nodes.lock().unwrap().set_health(&node, false);
+            }
+            gateway.breaker.lock().unwrap().record_failure();
+            Err(e)
+        }
+    }
+}
+
+///
+/// publishes the payload on the node of the routed channel, retrying transient errors with backoff.
+/// Returns the node with the channel id and link, or with the error of the last attempt
+///
+async fn attempt(
+    gateway: &Arc<Gateway>,
+    route: &Option<String>,
+    payload: &serde_json::Value,
+) -> std::result::Result<(String, String, String), (String, anyhow::Error)> {
+    let mut attempt = 0;
+    loop {
+        let (attempting, route, payload) = (gateway.clone(), route.clone(), payload.clone());
+        let result = task::spawn_blocking(move || {
+            let mut channel_state = attempting.channel_state.lock().unwrap();
+            let node = channel_state
+                .target(route.as_deref())
+                .map(|target| target.node.clone())
+                .unwrap_or_default();
+            let published = channel_state
+                .write_signed(route.as_deref(), &payload)
+                .map(|(channel_id, link)| (node.clone(), channel_id, link));
+            published.map_err(|e| (node, e))
+        })
+        .await
+        .map_err(|e| (String::new(), anyhow::Error::from(e)))
+        .and_then(|result| result);
+        match result {
+            Err((_, e)) if attempt < gateway.retry.max_retries && retry::is_transient(&e) => {
+                let delay = retry::backoff(&gateway.retry, attempt);
+                warn!(error = %e, attempt = attempt + 1, delay_ms = delay.as_millis() as u64, "publishing failed, retrying");
+                tokio::time::delay_for(delay).await;
+                attempt += 1;
+            }
+            _ => return result,
+        }
+    }
+}
+
+///
+/// opens a new channel on a healthy node other than the failed one in place of the routed channel,
+/// returning the id of the new channel
+///
+async fn move_channel(
+    gateway: &Arc<Gateway>,
+    route: Option<&str>,
+    failed: &str,
+) -> anyhow::Result<String> {
+    let (moving, route, failed) = (
+        gateway.clone(),
+        route.map(|route| route.to_string()),
+        failed.to_string(),
+    );
+    let channel_id = task::spawn_blocking(move || -> anyhow::Result<String> {
+        let seed = node_pool::generate_seed();
+        let (channel, channel_id, node) =
+            node_pool::open_channel_elsewhere(&moving.nodes, &seed, &failed)?;
+        let mut channel_state = moving.channel_state.lock().unwrap();
+        let target = channel_state.target_mut(route.as_deref())?;
+        let previous = mem::replace(
+            target,
+            PublishTarget {
+                channel: channel,
+                channel_id: channel_id.clone(),
+                seed: seed,
+                node: node,
+            },
+        );
+        moving.history.lock().unwrap().replaced(
+            &previous.channel_id,
+            &channel_id,
+            OpenReason::Failover,
+        );
+        Ok(channel_id)
+    })
+    .await??;
+    metrics::CHANNEL_SWITCHES.inc();
+    Ok(channel_id)
+}
+
+///
+/// the id of the routed channel, None selects the default channel. It is looked up on a blocking thread
+/// as the channel state stays locked while a message is published
+///
+pub async fn channel_id(gateway: &Arc<Gateway>, route: Option<&str>) -> anyhow::Result<String> {
+    let (gateway, route) = (gateway.clone(), route.map(|route| route.to_string()));
+    task::spawn_blocking(move || {
+        gateway
+            .channel_state
+            .lock()
+            .unwrap()
+            .target(route.as_deref())
+            .map(|target| target.channel_id.clone())
+    })
+    .await?
+}
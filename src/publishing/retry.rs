@@ -0,0 +1,316 @@
+use crate::types::config::RetryConfig;
+
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde_derive::Serialize;
+use tokio::time::Elapsed;
+
+///
+/// Error returned without contacting the node while the circuit breaker is open
+///
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "circuit breaker is open, the IOTA node failed repeatedly"
+        )
+    }
+}
+
+///
+/// Returns true for errors worth retrying, failures to reach the node or its timeouts found anywhere in the
+/// chain of causes: I/O errors of a transient kind, connection and timeout errors of hyper and elapsed timeouts.
+/// The open circuit breaker, serialization errors, messages rejected by the node and errors without such a cause
+/// are not retried
+///
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    if e.downcast_ref::<CircuitOpen>().is_some() {
+        return false;
+    }
+    e.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return transient_kind(e.kind());
+        }
+        if let Some(e) = cause.downcast_ref::<hyper::Error>() {
+            return e.is_connect() || e.is_timeout() || e.is_closed() || e.is_incomplete_message();
+        }
+        cause.is::<Elapsed>()
+    })
+}
+
+fn transient_kind(kind: io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+///
+/// Returns the time to wait before the given retry (starting at 0),
+/// chosen at random up to the exponentially growing limit ("full jitter")
+///
+pub fn backoff(config: &RetryConfig, retry: u32) -> Duration {
+    let limit = config
+        .base_delay_ms
+        .saturating_mul(1u64 << retry.min(16))
+        .min(config.max_delay_ms);
+    Duration::from_millis(rand::thread_rng().gen_range(0, limit + 1))
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+///
+/// Stops publishing after "breaker_threshold" consecutive failures. Once "breaker_reset" seconds passed
+/// a single attempt is let through (half-open), which closes the breaker again if it succeeds. Other attempts
+/// are rejected while the trial is in flight, unless it did not report back within "breaker_reset" seconds
+///
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: BreakerState,
+    failures: u32,
+    opened_at: Option<Instant>,
+    /// start of the trial let through while half-open
+    probing: Option<Instant>,
+    threshold: u32,
+    reset: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &RetryConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            state: BreakerState::Closed,
+            failures: 0,
+            opened_at: None,
+            probing: None,
+            threshold: config.breaker_threshold.max(1),
+            reset: Duration::from_secs(config.breaker_reset),
+        }
+    }
+
+    ///
+    /// returns true if a request may be sent to the node, moving an expired open breaker to half-open.
+    /// While half-open only the trial request is let through
+    ///
+    pub fn allow(&mut self) -> bool {
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::HalfOpen => {
+                if self
+                    .probing
+                    .map(|t| t.elapsed() >= self.reset)
+                    .unwrap_or(true)
+                {
+                    self.probing = Some(Instant::now());
+                    true
+                } else {
+                    false
+                }
+            }
+            BreakerState::Open => {
+                if self
+                    .opened_at
+                    .map(|t| t.elapsed() >= self.reset)
+                    .unwrap_or(true)
+                {
+                    self.state = BreakerState::HalfOpen;
+                    self.probing = Some(Instant::now());
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.state = BreakerState::Closed;
+        self.failures = 0;
+        self.opened_at = None;
+        self.probing = None;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.failures += 1;
+        self.probing = None;
+        if self.state == BreakerState::HalfOpen || self.failures >= self.threshold {
+            self.state = BreakerState::Open;
+            self.opened_at = Some(Instant::now());
+        }
+    }
+
+    ///
+    /// the state of the breaker as shown on /status
+    ///
+    pub fn status(&self) -> serde_json::Value {
+        let half_open_in = match (self.state, self.opened_at) {
+            (BreakerState::Open, Some(t)) => Some(self.reset.saturating_sub(t.elapsed()).as_secs()),
+            _ => None,
+        };
+        serde_json::json!({
+            "state": self.state,
+            "consecutive_failures": self.failures,
+            "seconds_until_half_open": half_open_in,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use std::collections::HashSet;
+
+    fn config(threshold: u32) -> RetryConfig {
+        RetryConfig {
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+            breaker_threshold: threshold,
+            breaker_reset: 30,
+            ..RetryConfig::default()
+        }
+    }
+
+    ///
+    /// lets the reset time of an open breaker pass
+    ///
+    fn expire(breaker: &mut CircuitBreaker) {
+        breaker.opened_at = Some(Instant::now() - Duration::from_secs(31));
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_maximum() {
+        let config = config(5);
+        for retry in 0..20 {
+            let limit = (100u64 << retry.min(16)).min(1000);
+            for _ in 0..50 {
+                assert!(backoff(&config, retry).as_millis() as u64 <= limit);
+            }
+        }
+        // full jitter spreads the delays over the whole range
+        let delays: HashSet<u128> = (0..50).map(|_| backoff(&config, 10).as_millis()).collect();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn breaker_opens_after_consecutive_failures() {
+        let mut breaker = CircuitBreaker::new(&config(3));
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.allow());
+        assert_eq!(breaker.state, BreakerState::Closed);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert!(!breaker.allow());
+        let half_open_in = breaker.status()["seconds_until_half_open"]
+            .as_u64()
+            .unwrap();
+        assert!(half_open_in > 0 && half_open_in <= 30);
+    }
+
+    #[test]
+    fn half_open_breaker_lets_a_single_trial_through() {
+        let mut breaker = CircuitBreaker::new(&config(1));
+        breaker.record_failure();
+        expire(&mut breaker);
+
+        assert!(breaker.allow());
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+        assert!(!breaker.allow());
+        assert!(!breaker.allow());
+
+        breaker.record_success();
+        assert_eq!(breaker.state, BreakerState::Closed);
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn failed_trial_opens_the_breaker_again() {
+        let mut breaker = CircuitBreaker::new(&config(1));
+        breaker.record_failure();
+        expire(&mut breaker);
+        assert!(breaker.allow());
+
+        breaker.record_failure();
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert!(!breaker.allow());
+
+        expire(&mut breaker);
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn trial_without_result_is_replaced_after_the_reset_time() {
+        let mut breaker = CircuitBreaker::new(&config(1));
+        breaker.record_failure();
+        expire(&mut breaker);
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+
+        breaker.probing = Some(Instant::now() - Duration::from_secs(31));
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn transient_io_errors_are_retried() {
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        assert!(is_transient(&anyhow::Error::from(refused)));
+
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "slow node");
+        let wrapped = Err::<(), _>(timed_out)
+            .context("could not send message")
+            .unwrap_err();
+        assert!(is_transient(&wrapped));
+
+        let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert!(!is_transient(&anyhow::Error::from(denied)));
+    }
+
+    #[tokio::test]
+    async fn elapsed_timeouts_are_retried() {
+        let elapsed =
+            tokio::time::timeout(Duration::from_millis(1), futures::future::pending::<()>())
+                .await
+                .unwrap_err();
+        assert!(is_transient(&anyhow::Error::from(elapsed)));
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        assert!(!is_transient(&anyhow::Error::msg(CircuitOpen)));
+        let malformed = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        assert!(!is_transient(&anyhow::Error::from(malformed)));
+        // only the type of the error counts, not its message
+        assert!(!is_transient(&anyhow::anyhow!("connection refused")));
+        assert!(!is_transient(&anyhow::anyhow!(
+            "message rejected by the node"
+        )));
+    }
+}
@@ -0,0 +1,106 @@
+use crate::device_auth::keystore::{self, KeyManager};
+use crate::monitoring::metrics;
+use crate::publishing::node_pool;
+use crate::publishing::routing::DEFAULT_CHANNEL;
+use crate::shutdown;
+use crate::types::channel_record::OpenReason;
+use crate::types::channel_state::PublishTarget;
+use crate::types::{config::Config, gateway::Gateway};
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::task;
+use tracing::{error, info};
+
+///
+/// Opens a new channel in place of the routed channel, None selects the default channel. The replaced channel is
+/// closed in the history and the seed of the new one stored in the keystore. Returns the id of the new channel
+///
+pub async fn replace_channel(
+    gateway: &Arc<Gateway>,
+    keystore: &Arc<Mutex<KeyManager>>,
+    config: &Config,
+    route: Option<&str>,
+    reason: OpenReason,
+) -> anyhow::Result<String> {
+    let seed = node_pool::generate_seed();
+    let (replacing, route_name, opening) = (
+        gateway.clone(),
+        route.map(|route| route.to_string()),
+        seed.clone(),
+    );
+    // opening the channel and waiting for a publish in progress both block
+    let channel_id = task::spawn_blocking(move || -> anyhow::Result<String> {
+        let (channel, channel_id, node) = node_pool::open_channel(&replacing.nodes, &opening)?;
+        let mut channel_state = replacing.channel_state.lock().unwrap();
+        let target = channel_state.target_mut(route_name.as_deref())?;
+        let previous = mem::replace(
+            target,
+            PublishTarget {
+                channel: channel,
+                channel_id: channel_id.clone(),
+                seed: opening,
+                node: node,
+            },
+        );
+        replacing
+            .history
+            .lock()
+            .unwrap()
+            .replaced(&previous.channel_id, &channel_id, reason);
+        Ok(channel_id)
+    })
+    .await??;
+    metrics::CHANNEL_SWITCHES.inc();
+    store_seed(keystore, config, route.unwrap_or(DEFAULT_CHANNEL), &seed);
+    Ok(channel_id)
+}
+
+///
+/// stores the seed of a new channel in the keystore, so it is opened again after a restart
+///
+fn store_seed(keystore: &Arc<Mutex<KeyManager>>, config: &Config, name: &str, seed: &str) {
+    let mut keystore = keystore.lock().unwrap();
+    keystore
+        .keystore
+        .seeds
+        .insert(name.to_string(), seed.to_string());
+    if let Err(e) =
+        keystore::passphrase(config).and_then(|passphrase| keystore.save(passphrase.as_deref()))
+    {
+        error!(error = %e, "could not store the channel seed in the keystore");
+    }
+}
+
+///
+/// Replaces the default and the routed channels every "channel_rotation" seconds until the shutdown is requested
+///
+pub async fn run(gateway: Arc<Gateway>, keystore: Arc<Mutex<KeyManager>>, config: Config) {
+    let interval = match config.channel_rotation {
+        Some(seconds) => Duration::from_secs(seconds),
+        None => return,
+    };
+    let mut names: Vec<Option<String>> = vec![None];
+    for rule in &config.routing {
+        if !names.contains(&Some(rule.channel.clone())) {
+            names.push(Some(rule.channel.clone()));
+        }
+    }
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                for name in &names {
+                    let channel = name.as_deref().unwrap_or(DEFAULT_CHANNEL);
+                    match replace_channel(&gateway, &keystore, &config, name.as_deref(), OpenReason::Rotation).await {
+                        Ok(channel_id) => info!(channel = %channel, channel_id = %channel_id, "channel rotated"),
+                        Err(e) => error!(channel = %channel, error = %e, "could not rotate channel, it is kept until the next rotation"),
+                    }
+                }
+            }
+            _ = shutdown::requested() => break,
+        }
+    }
+}
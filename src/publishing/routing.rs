@@ -0,0 +1,208 @@
+use crate::publishing::node_pool::{self, NodePool};
+use crate::publishing::publisher;
+use crate::types::bundle_data::BundleData;
+use crate::types::channel_state::PublishTarget;
+use crate::types::config::{Config, RoutingRule};
+use crate::types::gateway::Gateway;
+use crate::types::senml;
+use crate::types::sensor_data::SensorData;
+use crate::wifi_connectivity::event_stream::notify;
+use crate::wifi_connectivity::limits::check_message_size;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tracing::info;
+
+/// name of the channel of readings not matching any rule
+pub const DEFAULT_CHANNEL: &str = "default";
+
+///
+/// matches the value against a pattern where "*" stands for any number of characters
+///
+pub fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == value;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if value.len() < first.len() + last.len() || !value.starts_with(first) || !value.ends_with(last)
+    {
+        return false;
+    }
+    let mut rest = &value[first.len()..value.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(position) => rest = &rest[position + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+///
+/// the channel of the first rule matching the device and the sensor, None for the default channel
+///
+pub fn route<'a>(rules: &'a [RoutingRule], device_id: &str, sensor: &str) -> Option<&'a str> {
+    rules
+        .iter()
+        .find(|rule| {
+            (rule.devices.is_empty()
+                || rule
+                    .devices
+                    .iter()
+                    .any(|pattern| matches_pattern(pattern, device_id)))
+                && (rule.sensors.is_empty()
+                    || rule
+                        .sensors
+                        .iter()
+                        .any(|pattern| matches_pattern(pattern, sensor)))
+        })
+        .map(|rule| rule.channel.as_str())
+}
+
+///
+/// Splits the reading into one reading per channel, each keeping the sensors routed to its channel.
+/// The device id has to be the one sent by the device, the reading itself may carry its hash already
+///
+pub fn split(
+    rules: &[RoutingRule],
+    sensor_data: &SensorData,
+    device_id: &str,
+) -> Vec<(Option<String>, SensorData)> {
+    let mut parts: Vec<(Option<String>, SensorData)> = vec![];
+    for sensor in &sensor_data.iot2tangle {
+        let channel = route(rules, device_id, &sensor.sensor).map(|channel| channel.to_string());
+        match parts.iter_mut().find(|(route, _)| *route == channel) {
+            Some((_, part)) => part.iot2tangle.push(sensor.clone()),
+            None => parts.push((
+                channel,
+                SensorData {
+                    iot2tangle: vec![sensor.clone()],
+                    device: sensor_data.device.clone(),
+                    timestamp: sensor_data.timestamp.clone(),
+                },
+            )),
+        }
+    }
+    if parts.is_empty() {
+        parts.push((None, sensor_data.clone()));
+    }
+    parts
+}
+
+///
+/// Splits every reading of the bundle and groups the parts into one bundle per channel,
+/// device_ids holds the id sent by the device of each reading
+///
+pub fn split_bundle(
+    rules: &[RoutingRule],
+    bundle_data: &BundleData,
+    device_ids: &[String],
+) -> Vec<(Option<String>, BundleData)> {
+    let mut bundles: Vec<(Option<String>, BundleData)> = vec![];
+    for (sensor_data, device_id) in bundle_data.bundle.iter().zip(device_ids) {
+        for (channel, part) in split(rules, sensor_data, device_id) {
+            match bundles.iter_mut().find(|(route, _)| *route == channel) {
+                Some((_, bundle)) => bundle.bundle.push(part),
+                None => bundles.push((channel, BundleData { bundle: vec![part] })),
+            }
+        }
+    }
+    bundles
+}
+
+///
+/// Publishes the reading on the channels selected by the routing rules and notifies the feed of every part.
+/// The size of every part is checked before anything is published, a part too large for a message fails with its size.
+/// A failing channel does not stop the others, the result is returned for every channel by name
+///
+pub async fn publish(
+    gateway: &Arc<Gateway>,
+    sensor_data: &SensorData,
+    device_id: &str,
+    config: &Config,
+) -> Result<Vec<(String, anyhow::Result<String>)>, usize> {
+    let parts = split(&config.routing, sensor_data, device_id);
+    for (_, part) in &parts {
+        let oversized = if config.publish_senml {
+            check_message_size(&senml::sensor_data_to_pack(part), &config.limits)
+        } else {
+            check_message_size(part, &config.limits)
+        };
+        if let Some(size) = oversized {
+            return Err(size);
+        }
+    }
+
+    let mut channels = vec![];
+    for (route, part) in parts {
+        let published = if config.publish_senml {
+            let pack = senml::sensor_data_to_pack(&part);
+            publisher::write_signed(gateway, route.as_deref(), &pack).await
+        } else {
+            publisher::write_signed(gateway, route.as_deref(), &part).await
+        };
+        let result = match published {
+            Ok(published) => {
+                notify(gateway, &published.channel_id, &part, true);
+                Ok(published.channel_id)
+            }
+            Err(e) => {
+                if let Ok(channel_id) = publisher::channel_id(gateway, route.as_deref()).await {
+                    notify(gateway, &channel_id, &part, false);
+                }
+                Err(e)
+            }
+        };
+        channels.push((route.unwrap_or_else(|| DEFAULT_CHANNEL.to_string()), result));
+    }
+    Ok(channels)
+}
+
+///
+/// the results by channel name, the channel id of every channel published on and {"error": ...} for failed ones
+///
+pub fn channel_results<T: Into<serde_json::Value> + Clone>(
+    channels: &[(String, anyhow::Result<T>)],
+) -> serde_json::Value {
+    let results: serde_json::Map<String, serde_json::Value> = channels
+        .iter()
+        .map(|(name, result)| {
+            let result = match result {
+                Ok(published) => published.clone().into(),
+                Err(e) => serde_json::json!({ "error": e.to_string() }),
+            };
+            (name.clone(), result)
+        })
+        .collect();
+    serde_json::Value::Object(results)
+}
+
+///
+/// Opens a new channel with a new seed for every channel named in the routing rules
+///
+pub fn open_routes(
+    nodes: &Arc<Mutex<NodePool>>,
+    config: &Config,
+) -> anyhow::Result<HashMap<String, PublishTarget>> {
+    let mut routes = HashMap::new();
+    for rule in &config.routing {
+        if routes.contains_key(&rule.channel) {
+            continue;
+        }
+        let seed = node_pool::generate_seed();
+        let (channel, channel_id, node) = node_pool::open_channel(nodes, &seed)?;
+        info!(channel = %rule.channel, channel_id = %channel_id, "routed channel opened");
+        routes.insert(
+            rule.channel.clone(),
+            PublishTarget {
+                channel: channel,
+                channel_id: channel_id,
+                seed: seed,
+                node: node,
+            },
+        );
+    }
+    Ok(routes)
+}
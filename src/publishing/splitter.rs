@@ -0,0 +1,446 @@
+use crate::publishing::publisher;
+use crate::types::{
+    bundle_data::BundleData,
+    bundle_part::BundlePart,
+    config::Config,
+    gateway::Gateway,
+    senml::{self, SenmlRecord},
+};
+use crate::wifi_connectivity::limits::check_message_size;
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+pub enum BundleError {
+    /// a single item of the bundle does not fit into a message, with its size
+    TooLarge(usize),
+    Publish(PartialBundle),
+}
+
+#[derive(Debug)]
+pub struct PublishedBundle {
+    pub channel_id: String,
+    pub links: Vec<String>,
+    /// only set if the bundle was split into several messages
+    pub bundle_id: Option<String>,
+}
+
+///
+/// Bundle which could not be published completely, with the messages published before the error
+/// and the parts which are still to be published
+///
+pub struct PartialBundle {
+    pub error: anyhow::Error,
+    pub published: PublishedBundle,
+    pub remaining: Vec<BundlePart<serde_json::Value>>,
+}
+
+///
+/// Messages a bundle is published as, a single message or the parts of a bundle split to fit into messages
+///
+pub enum BundleMessages {
+    Single(serde_json::Value),
+    Parts(Vec<BundlePart<serde_json::Value>>),
+}
+
+///
+/// Prepares the bundle as a single message (as SenML pack if "publish_senml" is set),
+/// or split into several parts if it exceeds "max_message_size". Fails with the size of an item too large on its own
+///
+pub fn prepare(bundle_data: &BundleData, config: &Config) -> Result<BundleMessages, usize> {
+    if config.publish_senml {
+        let mut records: Vec<SenmlRecord> = bundle_data
+            .bundle
+            .iter()
+            .flat_map(senml::sensor_data_to_pack)
+            .collect();
+        let message = serde_json::to_value(&records).unwrap_or_default();
+        if check_message_size(&message, &config.limits).is_none() {
+            return Ok(BundleMessages::Single(message));
+        }
+        // every part has to carry the base name and time of its records
+        resolve_base_values(&mut records);
+        let parts = split(records, config.limits.max_message_size)?;
+        return Ok(BundleMessages::Parts(
+            parts
+                .into_iter()
+                .map(|mut part| {
+                    strip_repeated_base_values(&mut part.bundle);
+                    BundlePart {
+                        bundle_id: part.bundle_id,
+                        part: part.part,
+                        total: part.total,
+                        bundle: part
+                            .bundle
+                            .iter()
+                            .filter_map(|r| serde_json::to_value(r).ok())
+                            .collect(),
+                    }
+                })
+                .collect(),
+        ));
+    }
+
+    let items: Vec<serde_json::Value> = bundle_data
+        .bundle
+        .iter()
+        .filter_map(|d| serde_json::to_value(d).ok())
+        .collect();
+    let message = serde_json::json!({ "bundle": items });
+    if check_message_size(&message, &config.limits).is_none() {
+        return Ok(BundleMessages::Single(message));
+    }
+    split(items, config.limits.max_message_size).map(BundleMessages::Parts)
+}
+
+///
+/// Publishes the prepared messages of a bundle on the routed channel, None selects the default channel
+///
+pub async fn publish(
+    gateway: &Arc<Gateway>,
+    route: Option<&str>,
+    messages: &BundleMessages,
+) -> Result<PublishedBundle, PartialBundle> {
+    match messages {
+        BundleMessages::Single(message) => {
+            match publisher::write_signed(gateway, route, message).await {
+                Ok(published) => Ok(PublishedBundle {
+                    channel_id: published.channel_id,
+                    links: vec![published.link],
+                    bundle_id: None,
+                }),
+                Err(e) => Err(PartialBundle {
+                    error: e,
+                    published: PublishedBundle {
+                        channel_id: String::new(),
+                        links: vec![],
+                        bundle_id: None,
+                    },
+                    remaining: vec![],
+                }),
+            }
+        }
+        BundleMessages::Parts(parts) => {
+            let published = PublishedBundle {
+                channel_id: String::new(),
+                links: vec![],
+                bundle_id: parts.first().map(|part| part.bundle_id.clone()),
+            };
+            publish_parts(gateway, route, parts.clone(), published).await
+        }
+    }
+}
+
+///
+/// Prepares and publishes the bundle on the routed channel, None selects the default channel
+///
+pub async fn publish_bundle(
+    gateway: &Arc<Gateway>,
+    route: Option<&str>,
+    bundle_data: &BundleData,
+    config: &Config,
+) -> Result<PublishedBundle, BundleError> {
+    let messages = prepare(bundle_data, config).map_err(BundleError::TooLarge)?;
+    publish(gateway, route, &messages)
+        .await
+        .map_err(BundleError::Publish)
+}
+
+///
+/// Splits the items into parts which fit into a single message of max_size bytes once serialized.
+/// Fails with the size of the first item which is too large on its own
+///
+pub fn split<T: Serialize>(items: Vec<T>, max_size: usize) -> Result<Vec<BundlePart<T>>, usize> {
+    let bundle_id = format!("{:016x}", rand::random::<u64>());
+    // the numbers of a part never have more digits than the number of items
+    let empty = BundlePart::<T> {
+        bundle_id: bundle_id.clone(),
+        part: items.len(),
+        total: items.len(),
+        bundle: vec![],
+    };
+    let base = serialized_size(&empty);
+
+    let mut groups: Vec<Vec<T>> = vec![];
+    let mut current: Vec<T> = vec![];
+    let mut current_size = base;
+    for item in items {
+        let size = serialized_size(&item);
+        if base + size > max_size {
+            return Err(base + size);
+        }
+        // items are separated by a comma
+        let added = if current.is_empty() { size } else { size + 1 };
+        if current_size + added > max_size {
+            groups.push(current);
+            current = vec![];
+            current_size = base;
+        }
+        current_size += if current.is_empty() { size } else { size + 1 };
+        current.push(item);
+    }
+    if !current.is_empty() {
+        groups.push(current);
+    }
+
+    let total = groups.len();
+    Ok(groups
+        .into_iter()
+        .enumerate()
+        .map(|(i, bundle)| BundlePart {
+            bundle_id: bundle_id.clone(),
+            part: i + 1,
+            total: total,
+            bundle: bundle,
+        })
+        .collect())
+}
+
+///
+/// Publishes the parts one after another, adding the links of the messages to the bundle published so far.
+/// Stops at the first part which could not be published, the error keeps the links and the remaining parts
+///
+pub async fn publish_parts(
+    gateway: &Arc<Gateway>,
+    route: Option<&str>,
+    parts: Vec<BundlePart<serde_json::Value>>,
+    mut published: PublishedBundle,
+) -> Result<PublishedBundle, PartialBundle> {
+    let mut parts = parts.into_iter();
+    while let Some(part) = parts.next() {
+        match publisher::write_signed(gateway, route, &part).await {
+            Ok(message) => {
+                published.channel_id = message.channel_id;
+                published.links.push(message.link);
+            }
+            Err(e) => {
+                warn!(
+                    bundle_id = %part.bundle_id,
+                    part = part.part,
+                    total = part.total,
+                    published = ?published.links,
+                    "could not publish all parts of the bundle"
+                );
+                let mut remaining = vec![part];
+                remaining.extend(parts);
+                return Err(PartialBundle {
+                    error: e,
+                    published: published,
+                    remaining: remaining,
+                });
+            }
+        }
+    }
+    if let Some(bundle_id) = &published.bundle_id {
+        info!(bundle_id = %bundle_id, parts = published.links.len(), "bundle split into several messages");
+    }
+    Ok(published)
+}
+
+///
+/// SenML base values apply to all following records, so every record gets the base name and time it falls under
+/// before the pack is split
+///
+fn resolve_base_values(records: &mut [SenmlRecord]) {
+    let mut base_name = None;
+    let mut base_time = None;
+    for record in records {
+        if record.bn.is_some() {
+            base_name = record.bn.clone();
+        } else {
+            record.bn = base_name.clone();
+        }
+        if record.bt.is_some() {
+            base_time = record.bt;
+        } else {
+            record.bt = base_time;
+        }
+    }
+}
+
+///
+/// Removes the base values which repeat those of the previous record, only the first record of a part
+/// and the records of another device or time keep them
+///
+fn strip_repeated_base_values(records: &mut [SenmlRecord]) {
+    for i in (1..records.len()).rev() {
+        if records[i].bn == records[i - 1].bn {
+            records[i].bn = None;
+        }
+        if records[i].bt == records[i - 1].bt {
+            records[i].bt = None;
+        }
+    }
+}
+
+fn serialized_size<T: Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).map(|v| v.len()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::sensor_data::SensorData;
+
+    fn config(max_message_size: usize, publish_senml: bool) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "whitelisted_device_ids": ["DEVICE_ID_1"],
+            "port": 8080,
+            "node": "http://127.0.0.1:14265",
+            "local_pow": false,
+            "publish_senml": publish_senml,
+            "limits": {"max_message_size": max_message_size},
+        }))
+        .unwrap()
+    }
+
+    ///
+    /// a reading of the device with a single sensor and the given number of values
+    ///
+    fn reading(device: &str, values: usize) -> SensorData {
+        let data: Vec<serde_json::Value> = (0..values)
+            .map(|i| serde_json::json!({ format!("value{}", i): i.to_string() }))
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "iot2tangle": [{"sensor": "Gyroscope", "data": data}],
+            "device": device,
+            "timestamp": 1_600_000_000,
+        }))
+        .unwrap()
+    }
+
+    fn pack(device: &str, timestamp: f64, records: usize) -> Vec<SenmlRecord> {
+        let mut pack: Vec<SenmlRecord> = (0..records)
+            .map(|i| SenmlRecord {
+                n: Some(format!("sensor{}", i)),
+                u: Some("C".to_string()),
+                v: Some(i as f64),
+                ..Default::default()
+            })
+            .collect();
+        pack[0].bn = Some(device.to_string());
+        pack[0].bt = Some(timestamp);
+        pack
+    }
+
+    #[test]
+    fn every_senml_part_keeps_its_base_values() {
+        let mut records = pack("DEVICE_ID_1", 1.0, 5);
+        records.extend(pack("DEVICE_ID_2", 2.0, 5));
+        resolve_base_values(&mut records);
+        let parts = split(records, 200).expect("split");
+        assert!(parts.len() > 2);
+
+        let mut devices = vec![];
+        for mut part in parts {
+            assert!(serialized_size(&part) <= 200);
+            strip_repeated_base_values(&mut part.bundle);
+            assert!(serialized_size(&part) <= 200);
+            assert!(part.bundle[0].bn.is_some());
+            assert!(part.bundle[0].bt.is_some());
+            let mut current = (None, None);
+            for record in &part.bundle {
+                if record.bn.is_some() {
+                    current.0 = record.bn.clone();
+                }
+                if record.bt.is_some() {
+                    current.1 = record.bt;
+                }
+                devices.push(current.clone());
+            }
+        }
+        let expected: Vec<(Option<String>, Option<f64>)> = (0..10)
+            .map(|i| {
+                if i < 5 {
+                    (Some("DEVICE_ID_1".to_string()), Some(1.0))
+                } else {
+                    (Some("DEVICE_ID_2".to_string()), Some(2.0))
+                }
+            })
+            .collect();
+        assert_eq!(devices, expected);
+    }
+
+    #[test]
+    fn base_values_are_not_repeated_within_a_part() {
+        let mut records = pack("DEVICE_ID_1", 1.0, 3);
+        resolve_base_values(&mut records);
+        assert!(records.iter().all(|r| r.bn.is_some() && r.bt.is_some()));
+        strip_repeated_base_values(&mut records);
+        assert!(records[1..]
+            .iter()
+            .all(|r| r.bn.is_none() && r.bt.is_none()));
+    }
+
+    #[test]
+    fn every_sensor_data_part_fits_into_a_message() {
+        let bundle_data = BundleData {
+            bundle: (0..12)
+                .map(|i| reading(&format!("DEVICE_ID_{}", i), 1 + i % 4))
+                .collect(),
+        };
+        let parts = match prepare(&bundle_data, &config(400, false)) {
+            Ok(BundleMessages::Parts(parts)) => parts,
+            _ => panic!("bundle was not split"),
+        };
+        assert!(parts.len() > 2);
+
+        let mut devices = vec![];
+        for (i, part) in parts.iter().enumerate() {
+            assert!(serialized_size(part) <= 400, "part {} too large", i + 1);
+            assert_eq!((part.part, part.total), (i + 1, parts.len()));
+            assert_eq!(part.bundle_id, parts[0].bundle_id);
+            for item in &part.bundle {
+                devices.push(item["device"].as_str().unwrap().to_string());
+            }
+        }
+        let expected: Vec<String> = (0..12).map(|i| format!("DEVICE_ID_{}", i)).collect();
+        assert_eq!(devices, expected);
+    }
+
+    #[test]
+    fn every_senml_part_fits_into_a_message() {
+        let bundle_data = BundleData {
+            bundle: (0..8).map(|_| reading("DEVICE_ID_1", 3)).collect(),
+        };
+        let parts = match prepare(&bundle_data, &config(300, true)) {
+            Ok(BundleMessages::Parts(parts)) => parts,
+            _ => panic!("bundle was not split"),
+        };
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(serialized_size(part) <= 300);
+        }
+    }
+
+    #[test]
+    fn sensor_entry_larger_than_a_message_is_rejected() {
+        let bundle_data = BundleData {
+            bundle: vec![reading("DEVICE_ID_1", 1), reading("DEVICE_ID_2", 40)],
+        };
+        let size = match prepare(&bundle_data, &config(400, false)) {
+            Err(size) => size,
+            Ok(_) => panic!("oversized entry was accepted"),
+        };
+        assert!(size > 400);
+
+        // the same entry fits once the limit is large enough
+        assert!(prepare(&bundle_data, &config(size, false)).is_ok());
+    }
+
+    #[test]
+    fn bundle_within_the_limit_stays_a_single_message() {
+        let bundle_data = BundleData {
+            bundle: vec![reading("DEVICE_ID_1", 2)],
+        };
+        match prepare(&bundle_data, &config(400, false)) {
+            Ok(BundleMessages::Single(message)) => {
+                assert!(serialized_size(&message) <= 400);
+                assert_eq!(message["bundle"][0]["device"], "DEVICE_ID_1");
+            }
+            _ => panic!("bundle was split"),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::cli::Options;
+use crate::device_auth::keystore::KeyManager;
+use crate::monitoring::logging;
+use crate::types::config::Config;
+
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// interval in which the configuration file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+///
+/// Reloads the configuration when the file changes or the process receives SIGHUP.
+/// The whitelisted devices and readers (or the restored keystore) replace the keystore and the log level is changed live,
+/// the open channel is kept. Invalid configurations are rejected and the previous one stays active
+///
+pub async fn watch(options: Options, mut current: Config, keystore: Arc<Mutex<KeyManager>>) {
+    let (sender, mut hangups) = mpsc::channel(1);
+    listen_for_hangup(sender);
+
+    let mut ticks = tokio::time::interval(WATCH_INTERVAL);
+    let mut modified = modified_at(&options.config);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                let now = modified_at(&options.config);
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+                info!("configuration file changed");
+            }
+            Some(_) = hangups.recv() => info!("SIGHUP received"),
+        }
+        reload(&options, &mut current, &keystore);
+    }
+}
+
+fn reload(options: &Options, current: &mut Config, keystore: &Arc<Mutex<KeyManager>>) {
+    let config = match options.load_config() {
+        Ok(config) => config,
+        Err(errors) => {
+            for e in errors {
+                warn!(error = %e, "invalid configuration");
+            }
+            warn!("configuration not reloaded, keeping the previous one");
+            return;
+        }
+    };
+
+    // a restored keystore may have been changed on disk, so it is read again on every reload
+    if config.restore_keystore
+        || config.whitelisted_device_ids != current.whitelisted_device_ids
+        || config.whitelisted_reader_ids != current.whitelisted_reader_ids
+    {
+        // the seeds of the open channels are kept, a regenerated keystore does not know them
+        let seeds = keystore.lock().unwrap().keystore.seeds.clone();
+        match KeyManager::from_config(&config, Some(seeds)) {
+            Ok(manager) => {
+                info!(
+                    devices = manager.keystore.api_keys_author.len(),
+                    readers = manager.keystore.api_keys_reader.len(),
+                    "whitelist reloaded"
+                );
+                *keystore.lock().unwrap() = manager;
+            }
+            Err(e) => {
+                error!(error = %e, "could not reload the keystore, keeping the previous one");
+                return;
+            }
+        }
+    }
+
+    if config.log_level != current.log_level {
+        match logging::set_level(&config.log_level) {
+            Ok(_) => info!(level = %config.log_level, "log level changed"),
+            Err(e) => error!(error = %e, "could not change the log level"),
+        }
+    }
+
+    if requires_restart(&config, current) {
+        warn!("some changes of the configuration only take effect after a restart");
+    }
+    *current = config;
+}
+
+///
+/// checks if anything besides the settings applied live has changed
+///
+fn requires_restart(config: &Config, current: &Config) -> bool {
+    let mut changed = config.clone();
+    changed.whitelisted_device_ids = current.whitelisted_device_ids.clone();
+    changed.whitelisted_reader_ids = current.whitelisted_reader_ids.clone();
+    changed.log_level = current.log_level.clone();
+    serde_json::to_value(&changed).ok() != serde_json::to_value(current).ok()
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(unix)]
+fn listen_for_hangup(mut sender: mpsc::Sender<()>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!(error = %e, "could not listen for SIGHUP");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if sender.send(()).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn listen_for_hangup(_sender: mpsc::Sender<()>) {}
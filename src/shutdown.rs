@@ -0,0 +1,144 @@
+use crate::device_auth::keystore::{self, KeyManager};
+use crate::publishing::{anchoring, batcher};
+use crate::types::{channel_state::ChannelState, config::Config, gateway::Gateway};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// interval in which the channel is checked for a publish in progress while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+lazy_static! {
+    static ref SHUTDOWN: (watch::Sender<bool>, watch::Receiver<bool>) = watch::channel(false);
+    static ref REQUESTED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+///
+/// Waits for SIGINT or SIGTERM and asks all servers to stop accepting new requests
+///
+pub async fn listen_for_signals() {
+    wait_for_signal().await;
+    info!("shutdown requested");
+    trigger();
+}
+
+///
+/// asks all servers to stop accepting new requests
+///
+pub fn trigger() {
+    REQUESTED_AT
+        .lock()
+        .unwrap()
+        .get_or_insert_with(Instant::now);
+    let _ = SHUTDOWN.0.broadcast(true);
+}
+
+pub fn is_requested() -> bool {
+    *SHUTDOWN.1.borrow()
+}
+
+///
+/// completes once the shutdown was requested
+///
+pub async fn requested() {
+    let mut receiver = SHUTDOWN.1.clone();
+    while !*receiver.borrow() {
+        if receiver.recv().await.is_none() {
+            return;
+        }
+    }
+}
+
+///
+/// time left until the deadline for the shutdown is reached
+///
+pub fn remaining(timeout: Duration) -> Duration {
+    match *REQUESTED_AT.lock().unwrap() {
+        Some(requested_at) => timeout
+            .checked_sub(requested_at.elapsed())
+            .unwrap_or_else(|| Duration::from_secs(0)),
+        None => timeout,
+    }
+}
+
+///
+/// Waits until the publish in progress is finished, publishes the readings still waiting in the batch
+/// or for anchoring, closes the channels in the history and persists the keystore.
+/// Returns the lock on the channel, holding it until the process exits makes sure no further message is sent
+/// after draining
+///
+pub async fn drain<'a>(
+    gateway: &'a Arc<Gateway>,
+    keystore: &Arc<Mutex<KeyManager>>,
+    config: &Config,
+) -> Option<std::sync::MutexGuard<'a, ChannelState>> {
+    let timeout = remaining(Duration::from_secs(config.shutdown_timeout));
+    let finished = tokio::time::timeout(timeout, async {
+        loop {
+            if let Ok(channel_state) = gateway.channel_state.try_lock() {
+                return channel_state.default.channel_id.clone();
+            }
+            tokio::time::delay_for(DRAIN_POLL_INTERVAL).await;
+        }
+    })
+    .await;
+    let channel_state = match finished {
+        Ok(channel_id) => {
+            info!(channel_id = %channel_id, "in-flight publishes finished");
+            batcher::flush(gateway, config).await;
+            anchoring::anchor(gateway).await;
+            let locked = gateway.channel_state.lock().unwrap();
+            gateway.history.lock().unwrap().close_all();
+            Some(locked)
+        }
+        Err(_) => {
+            warn!("shutdown deadline reached while publishing, the message may be lost");
+            None
+        }
+    };
+
+    let passphrase = match keystore::passphrase(config) {
+        Ok(passphrase) => passphrase,
+        Err(e) => {
+            error!(error = %e, "could not persist the keystore");
+            return channel_state;
+        }
+    };
+    let mut keystore = keystore.lock().unwrap();
+    // channels moved to another node while publishing got new seeds
+    if let Some(channel_state) = &channel_state {
+        keystore.keystore.seeds = channel_state.seeds();
+    }
+    match keystore.save(passphrase.as_deref()) {
+        Ok(_) => info!("keystore persisted"),
+        Err(e) => error!(error = %e, "could not persist the keystore"),
+    }
+    channel_state
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(e) => {
+            warn!(error = %e, "could not listen for SIGTERM");
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
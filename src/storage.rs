@@ -0,0 +1,56 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+///
+/// Writes the data to a file next to the path which is then renamed, so the stored file is never left partially
+/// written. With "owner_only" the file is readable only by its owner
+///
+pub fn write_atomic(path: &Path, data: &[u8], owner_only: bool) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let write = || -> io::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        if owner_only {
+            owner_only_mode(&mut options);
+        }
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(data)?;
+        file.flush()?;
+        file.sync_all()?;
+        if owner_only {
+            restrict_permissions(&tmp_path)?;
+        }
+        fs::rename(&tmp_path, path)
+    };
+    write().map_err(|e| {
+        let _ = fs::remove_file(&tmp_path);
+        e
+    })
+}
+
+#[cfg(unix)]
+fn owner_only_mode(options: &mut OpenOptions) {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+}
+
+#[cfg(not(unix))]
+fn owner_only_mode(_options: &mut OpenOptions) {}
+
+///
+/// the mode is only applied to new files, an existing temp file keeps its permissions otherwise
+///
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
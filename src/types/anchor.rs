@@ -0,0 +1,76 @@
+use crate::types::sensor_data::SensorData;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+///
+/// Message published on the channel in anchoring mode, committing to the readings of one batch
+///
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnchorMessage {
+    pub merkle_root: String,
+    pub readings: usize,
+    pub anchored_at: u64,
+}
+
+///
+/// Reading kept in the local store of the anchoring mode
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredReading {
+    pub reading_id: String,
+    pub received_at: u64,
+    pub reading: SensorData,
+}
+
+///
+/// Batch of readings whose Merkle root was published, the order of reading_ids is the order of the leaves
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnchoredBatch {
+    pub merkle_root: String,
+    pub channel_id: String,
+    pub message_link: String,
+    pub anchored_at: u64,
+    pub reading_ids: Vec<String>,
+}
+
+///
+/// Entry of the anchor store file, one JSON object per line
+///
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AnchorRecord {
+    Reading(StoredReading),
+    Anchored(AnchoredBatch),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+///
+/// Sibling hash on the way from a leaf to the root, "side" tells on which side the sibling is
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProofStep {
+    pub hash: String,
+    pub side: Side,
+}
+
+///
+/// Proof that the reading is included in the Merkle root published with the message at message_link
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InclusionProof {
+    pub reading_id: String,
+    pub reading: SensorData,
+    pub leaf: String,
+    pub path: Vec<ProofStep>,
+    pub merkle_root: String,
+    pub channel_id: String,
+    pub message_link: String,
+    pub anchored_at: u64,
+}
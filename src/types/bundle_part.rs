@@ -0,0 +1,13 @@
+use serde_derive::Serialize;
+
+///
+/// Part of a bundle which was too large for a single Streams message,
+/// the parts of a bundle share the bundle_id and are numbered from 1 to total
+///
+#[derive(Serialize, Debug, Clone)]
+pub struct BundlePart<T> {
+    pub bundle_id: String,
+    pub part: usize,
+    pub total: usize,
+    pub bundle: Vec<T>,
+}
@@ -0,0 +1,24 @@
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenReason {
+    Startup,
+    ManualSwitch,
+    Rotation,
+    Failover,
+}
+
+///
+/// Channel opened by the gateway, "name" is the routing channel it served and "messages" the number published on it
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelRecord {
+    pub channel_id: String,
+    pub name: String,
+    pub reason: OpenReason,
+    pub opened_at: u64,
+    pub closed_at: Option<u64>,
+    pub messages: u64,
+}
@@ -1,6 +1,87 @@
+use crate::monitoring::metrics;
+use crate::publishing::routing::DEFAULT_CHANNEL;
 use gateway_core::gateway::publisher::Channel;
+use std::collections::HashMap;
 
-pub struct ChannelState {
+///
+/// Channel messages are published on, the default channel or one named in the routing rules
+///
+pub struct PublishTarget {
     pub channel: Channel,
     pub channel_id: String,
+    pub seed: String,
+    /// node the channel was opened on
+    pub node: String,
+}
+
+impl PublishTarget {
+    ///
+    /// publishes the payload as signed message on the channel, recording the time it took and failures of the node.
+    /// Blocks during the proof of work, the publisher runs it on a blocking thread
+    ///
+    pub fn write_signed(&mut self, payload: &serde_json::Value) -> anyhow::Result<String> {
+        let timer = metrics::PUBLISH_DURATION.start_timer();
+        let result = self.channel.write_signed(payload);
+        timer.observe_duration();
+        if result.is_err() {
+            metrics::NODE_ERRORS.inc();
+        }
+        result
+    }
+}
+
+pub struct ChannelState {
+    pub default: PublishTarget,
+    pub routes: HashMap<String, PublishTarget>,
+}
+
+impl ChannelState {
+    ///
+    /// the channel named in the routing rules, None selects the default channel
+    ///
+    pub fn target(&self, route: Option<&str>) -> anyhow::Result<&PublishTarget> {
+        match route {
+            None => Ok(&self.default),
+            Some(name) => self
+                .routes
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("Unknown channel {}", name)),
+        }
+    }
+
+    pub fn target_mut(&mut self, route: Option<&str>) -> anyhow::Result<&mut PublishTarget> {
+        match route {
+            None => Ok(&mut self.default),
+            Some(name) => self
+                .routes
+                .get_mut(name)
+                .ok_or_else(|| anyhow::anyhow!("Unknown channel {}", name)),
+        }
+    }
+
+    ///
+    /// publishes the payload once on the routed channel, returning the id of the channel and the link of the message
+    ///
+    pub fn write_signed(
+        &mut self,
+        route: Option<&str>,
+        payload: &serde_json::Value,
+    ) -> anyhow::Result<(String, String)> {
+        let target = self.target_mut(route)?;
+        let link = target.write_signed(payload)?;
+        Ok((target.channel_id.clone(), link))
+    }
+
+    ///
+    /// the seeds of all channels by name, stored in the keystore
+    ///
+    pub fn seeds(&self) -> HashMap<String, String> {
+        let mut seeds: HashMap<String, String> = self
+            .routes
+            .iter()
+            .map(|(name, target)| (name.clone(), target.seed.clone()))
+            .collect();
+        seeds.insert(DEFAULT_CHANNEL.to_string(), self.default.seed.clone());
+        seeds
+    }
 }
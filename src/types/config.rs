@@ -1,10 +1,640 @@
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 
+use hyper::Uri;
+use tracing_subscriber::EnvFilter;
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+/// prefix of the environment variables overriding the configuration file
+static ENV_PREFIX: &str = "STREAMS_GATEWAY_";
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub whitelisted_device_ids: Vec<String>,
+    #[serde(default)]
+    pub whitelisted_reader_ids: Vec<String>,
+    #[serde(default = "default_keystore_path")]
+    pub keystore_path: String,
+    #[serde(default)]
+    pub restore_keystore: bool,
+    #[serde(default)]
+    pub keystore_passphrase_file: Option<String>,
+    #[serde(default = "default_channel_history_path")]
+    pub channel_history_path: String,
+    #[serde(default)]
+    pub channel_rotation: Option<u64>,
     pub port: u16,
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
     pub node: String,
+    #[serde(default)]
+    pub nodes: Vec<NodeConfig>,
+    #[serde(default = "default_node_probe_interval")]
+    pub node_probe_interval: u64,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub batching: Option<BatchingConfig>,
+    #[serde(default)]
+    pub anchoring: Option<AnchoringConfig>,
+    #[serde(default)]
+    pub field_encryption: Vec<FieldEncryptionRule>,
+    #[serde(default)]
+    pub routing: Vec<RoutingRule>,
     pub local_pow: bool,
+    #[serde(default)]
+    pub publish_senml: bool,
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub coap_port: Option<u16>,
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    #[serde(default = "default_log_format")]
+    pub log_format: String,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+}
+
+impl Config {
+    ///
+    /// Reads the configuration file, the format is chosen by the extension (.json, .toml, .yaml or .yml)
+    ///
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let config: std::result::Result<Config, GenericError> =
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("toml") => toml::from_str(&content).map_err(|e| e.into()),
+                Some("yaml") | Some("yml") => serde_yaml::from_str(&content).map_err(|e| e.into()),
+                _ => serde_json::from_str(&content).map_err(|e| e.into()),
+            };
+        config.map_err(|e| format!("Could not parse {}: {}", path.display(), e).into())
+    }
+
+    ///
+    /// Checks the values of the configuration, returns a description of every problem found
+    ///
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        check_ids(
+            "whitelisted_device_ids",
+            &self.whitelisted_device_ids,
+            &mut errors,
+        );
+        if self.whitelisted_device_ids.is_empty() && !self.restore_keystore {
+            errors.push("whitelisted_device_ids is empty, no device could send data".to_string());
+        }
+        check_ids(
+            "whitelisted_reader_ids",
+            &self.whitelisted_reader_ids,
+            &mut errors,
+        );
+        if self.keystore_path.trim().is_empty() {
+            errors.push("keystore_path is empty".to_string());
+        } else if self.restore_keystore && !Path::new(&self.keystore_path).is_file() {
+            errors.push(format!(
+                "keystore_path \"{}\" does not exist, it is needed because restore_keystore is set",
+                self.keystore_path
+            ));
+        }
+
+        if self.channel_history_path.trim().is_empty() {
+            errors.push("channel_history_path is empty".to_string());
+        }
+        if self.channel_rotation == Some(0) {
+            errors.push("channel_rotation must be at least 1 second".to_string());
+        }
+
+        if self.port == 0 {
+            errors.push("port must be between 1 and 65535".to_string());
+        }
+        if self.coap_port == Some(0) {
+            errors.push("coap_port must be between 1 and 65535".to_string());
+        }
+        if self.bind_address.parse::<IpAddr>().is_err() {
+            errors.push(format!(
+                "bind_address \"{}\" is not an IP address, e.g. 0.0.0.0",
+                self.bind_address
+            ));
+        }
+
+        check_node_url("node", &self.node, &mut errors);
+        for (i, node) in self.nodes.iter().enumerate() {
+            check_node_url(&format!("nodes[{}].url", i), &node.url, &mut errors);
+        }
+        if self.node_probe_interval == 0 {
+            errors.push("node_probe_interval must be at least 1 second".to_string());
+        }
+
+        if self.retry.base_delay_ms > self.retry.max_delay_ms {
+            errors
+                .push("retry.base_delay_ms must not be larger than retry.max_delay_ms".to_string());
+        }
+        if self.retry.breaker_threshold == 0 {
+            errors.push("retry.breaker_threshold must be at least 1".to_string());
+        }
+
+        if self.limits.max_body_size == 0 || self.limits.max_bundle_size == 0 {
+            errors.push(
+                "limits.max_body_size and limits.max_bundle_size must be larger than 0".to_string(),
+            );
+        }
+        if self.limits.max_message_size == 0 {
+            errors.push("limits.max_message_size must be larger than 0".to_string());
+        }
+        if self.limits.header_timeout == 0 || self.limits.read_timeout == 0 {
+            errors.push(
+                "limits.header_timeout and limits.read_timeout must be at least 1 second"
+                    .to_string(),
+            );
+        }
+
+        if let Some(batching) = &self.batching {
+            if batching.interval == 0 {
+                errors.push("batching.interval must be at least 1 second".to_string());
+            }
+            if batching.max_readings == 0 {
+                errors.push("batching.max_readings must be at least 1".to_string());
+            }
+            if batching.max_pending < batching.max_readings {
+                errors.push(format!(
+                    "batching.max_pending must be at least max_readings ({})",
+                    batching.max_readings
+                ));
+            }
+        }
+
+        if let Some(anchoring) = &self.anchoring {
+            if anchoring.interval == 0 {
+                errors.push("anchoring.interval must be at least 1 second".to_string());
+            }
+            if anchoring.retention == 0 {
+                errors.push("anchoring.retention must be at least 1 second".to_string());
+            }
+            if anchoring.store_path.is_empty() {
+                errors.push("anchoring.store_path must not be empty".to_string());
+            }
+            if self.batching.is_some() {
+                errors.push("batching and anchoring can not be used together".to_string());
+            }
+        }
+
+        for (i, rule) in self.field_encryption.iter().enumerate() {
+            if rule.tenant.trim().is_empty() || rule.tenant.contains('/') {
+                errors.push(format!(
+                    "field_encryption[{}].tenant must not be empty or contain \"/\"",
+                    i
+                ));
+            }
+            if rule.key_file.trim().is_empty() {
+                errors.push(format!("field_encryption[{}].key_file is empty", i));
+            } else if !Path::new(&rule.key_file).is_file() {
+                errors.push(format!(
+                    "field_encryption[{}].key_file \"{}\" does not exist",
+                    i, rule.key_file
+                ));
+            }
+            if rule.sensors.is_empty() && rule.fields.is_empty() {
+                errors.push(format!(
+                    "field_encryption[{}] selects neither sensors nor fields",
+                    i
+                ));
+            }
+        }
+
+        for (i, rule) in self.routing.iter().enumerate() {
+            if rule.channel.trim().is_empty() || rule.channel == "default" {
+                errors.push(format!(
+                    "routing[{}].channel must not be empty or \"default\"",
+                    i
+                ));
+            }
+            if rule.devices.is_empty() && rule.sensors.is_empty() {
+                errors.push(format!(
+                    "routing[{}] matches neither devices nor sensors",
+                    i
+                ));
+            }
+        }
+        if !self.routing.is_empty() && (self.batching.is_some() || self.anchoring.is_some()) {
+            errors.push("routing can not be used together with batching or anchoring".to_string());
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.log_level) {
+            errors.push(format!(
+                "log_level \"{}\" is invalid: {}",
+                self.log_level, e
+            ));
+        }
+        if self.log_format != "pretty" && self.log_format != "json" {
+            errors.push(format!(
+                "log_format \"{}\" is unknown, use \"pretty\" or \"json\"",
+                self.log_format
+            ));
+        }
+
+        if let Some(mqtt) = &self.mqtt {
+            if mqtt.host.is_empty() {
+                errors.push("mqtt.host is empty".to_string());
+            }
+            if mqtt.port == 0 {
+                errors.push("mqtt.port must be between 1 and 65535".to_string());
+            }
+            if mqtt.password.is_some() && mqtt.username.is_none() {
+                errors
+                    .push("mqtt.password can only be used together with mqtt.username".to_string());
+            }
+            if mqtt.topics.is_empty() {
+                errors.push("mqtt.topics is empty, no data would be received".to_string());
+            }
+        }
+        errors
+    }
+
+    ///
+    /// Overrides the values of the configuration file with the STREAMS_GATEWAY_* environment variables,
+    /// e.g. STREAMS_GATEWAY_PORT=8081. Lists are separated by commas
+    ///
+    pub fn apply_env(&mut self) -> Result<()> {
+        override_list("WHITELISTED_DEVICE_IDS", &mut self.whitelisted_device_ids);
+        override_list("WHITELISTED_READER_IDS", &mut self.whitelisted_reader_ids);
+        override_value("KEYSTORE_PATH", &mut self.keystore_path)?;
+        override_value("RESTORE_KEYSTORE", &mut self.restore_keystore)?;
+        if let Some(file) = env_var("KEYSTORE_PASSPHRASE_FILE") {
+            self.keystore_passphrase_file = Some(file);
+        }
+        override_value("CHANNEL_HISTORY_PATH", &mut self.channel_history_path)?;
+        override_value("PORT", &mut self.port)?;
+        override_value("BIND_ADDRESS", &mut self.bind_address)?;
+        if let Some(node) = env_var("NODE") {
+            // a single node replaces the fallback nodes of the configuration file, like --node
+            self.node = node;
+            self.nodes.clear();
+        }
+        override_value("NODE_PROBE_INTERVAL", &mut self.node_probe_interval)?;
+        override_value("LOCAL_POW", &mut self.local_pow)?;
+        override_value("PUBLISH_SENML", &mut self.publish_senml)?;
+        override_value("LOG_LEVEL", &mut self.log_level)?;
+        override_value("LOG_FORMAT", &mut self.log_format)?;
+        override_value("DRY_RUN", &mut self.dry_run)?;
+        override_value("SHUTDOWN_TIMEOUT", &mut self.shutdown_timeout)?;
+        if let Some(rotation) = env_var("CHANNEL_ROTATION") {
+            self.channel_rotation =
+                Some(rotation.parse().map_err(|_| invalid("CHANNEL_ROTATION"))?);
+        }
+        if let Some(port) = env_var("COAP_PORT") {
+            self.coap_port = Some(port.parse().map_err(|_| invalid("COAP_PORT"))?);
+        }
+        Ok(())
+    }
+}
+
+///
+/// Checks that the configuration file, which holds the whitelisted ids, can only be changed by its owner
+/// and is not readable by others if it contains the MQTT password
+///
+#[cfg(unix)]
+pub fn check_permissions(path: &Path, config: &Config) -> Vec<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut errors = Vec::new();
+    if let Ok(metadata) = fs::metadata(path) {
+        let mode = metadata.permissions().mode();
+        if mode & 0o022 != 0 {
+            errors.push(format!(
+                "{} is writable by other users, run: chmod go-w {}",
+                path.display(),
+                path.display()
+            ));
+        }
+        let has_password = config
+            .mqtt
+            .as_ref()
+            .map(|mqtt| mqtt.password.is_some())
+            .unwrap_or(false);
+        if has_password && mode & 0o004 != 0 {
+            errors.push(format!(
+                "{} contains the MQTT password and is readable by all users, run: chmod o-r {}",
+                path.display(),
+                path.display()
+            ));
+        }
+    }
+    errors
+}
+
+#[cfg(not(unix))]
+pub fn check_permissions(_path: &Path, _config: &Config) -> Vec<String> {
+    Vec::new()
+}
+
+fn check_ids(name: &str, ids: &[String], errors: &mut Vec<String>) {
+    let mut seen = HashSet::new();
+    for id in ids {
+        if id.trim().is_empty() {
+            errors.push(format!("{} contains an empty id", name));
+        } else if !seen.insert(id) {
+            errors.push(format!("{} contains \"{}\" more than once", name, id));
+        }
+    }
+}
+
+fn check_node_url(name: &str, url: &str, errors: &mut Vec<String>) {
+    match url.parse::<Uri>() {
+        Ok(uri) if uri.host().is_some() && uri.scheme_str().is_some() => {
+            if uri.scheme_str() != Some("http") && uri.scheme_str() != Some("https") {
+                errors.push(format!(
+                    "{} \"{}\" must use http or https, e.g. https://nodes.iota.org:443",
+                    name, url
+                ));
+            }
+        }
+        _ => errors.push(format!(
+            "{} \"{}\" is not a valid URL, e.g. https://nodes.iota.org:443",
+            name, url
+        )),
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(format!("{}{}", ENV_PREFIX, name)).ok()
+}
+
+fn invalid(name: &str) -> GenericError {
+    format!("Invalid value for {}{}", ENV_PREFIX, name).into()
+}
+
+fn override_value<T: FromStr>(name: &str, value: &mut T) -> Result<()> {
+    if let Some(var) = env_var(name) {
+        *value = var.parse().map_err(|_| invalid(name))?;
+    }
+    Ok(())
+}
+
+fn override_list(name: &str, list: &mut Vec<String>) {
+    if let Some(var) = env_var(name) {
+        *list = var
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect();
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeConfig {
+    pub url: String,
+    #[serde(default)]
+    pub priority: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetryConfig {
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_base_delay_ms")]
+    pub base_delay_ms: u64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    #[serde(default = "default_breaker_threshold")]
+    pub breaker_threshold: u32,
+    #[serde(default = "default_breaker_reset")]
+    pub breaker_reset: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: default_max_retries(),
+            base_delay_ms: default_base_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+            breaker_threshold: default_breaker_threshold(),
+            breaker_reset: default_breaker_reset(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LimitsConfig {
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    #[serde(default = "default_max_bundle_size")]
+    pub max_bundle_size: usize,
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    #[serde(default = "default_header_timeout")]
+    pub header_timeout: u64,
+    #[serde(default = "default_read_timeout")]
+    pub read_timeout: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_body_size: default_max_body_size(),
+            max_bundle_size: default_max_bundle_size(),
+            max_message_size: default_max_message_size(),
+            header_timeout: default_header_timeout(),
+            read_timeout: default_read_timeout(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchingConfig {
+    #[serde(default = "default_batch_interval")]
+    pub interval: u64,
+    #[serde(default = "default_batch_max_readings")]
+    pub max_readings: usize,
+    #[serde(default = "default_receipt_ttl")]
+    pub receipt_ttl: u64,
+    /// readings kept while the node is failing, further readings are rejected
+    #[serde(default = "default_batch_max_pending")]
+    pub max_pending: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnchoringConfig {
+    #[serde(default = "default_anchor_interval")]
+    pub interval: u64,
+    #[serde(default = "default_anchor_store_path")]
+    pub store_path: String,
+    #[serde(default = "default_anchor_retention")]
+    pub retention: u64,
+}
+
+///
+/// Values of the devices (all if empty) to encrypt with the key of the tenant,
+/// "sensors" selects whole SensorType entries and "fields" keys inside their data
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FieldEncryptionRule {
+    pub tenant: String,
+    pub key_file: String,
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default)]
+    pub sensors: Vec<String>,
+    #[serde(default)]
+    pub fields: Vec<String>,
+}
+
+///
+/// Sends the sensors matching the rule to the named channel, "devices" and "sensors" are patterns
+/// where "*" matches any characters and an empty list matches everything
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoutingRule {
+    pub channel: String,
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default)]
+    pub sensors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub topics: Vec<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive: u16,
+    #[serde(default = "default_mqtt_reconnect_interval")]
+    pub reconnect_interval: u64,
+}
+
+fn default_keystore_path() -> String {
+    "keystore.json".to_string()
+}
+
+fn default_channel_history_path() -> String {
+    "channels.json".to_string()
+}
+
+fn default_bind_address() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_node_probe_interval() -> u64 {
+    30
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_base_delay_ms() -> u64 {
+    200
+}
+
+fn default_max_delay_ms() -> u64 {
+    5000
+}
+
+fn default_breaker_threshold() -> u32 {
+    5
+}
+
+fn default_breaker_reset() -> u64 {
+    30
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_max_body_size() -> usize {
+    64 * 1024
+}
+
+fn default_max_bundle_size() -> usize {
+    1024 * 1024
+}
+
+/// a Chrysalis message is limited to 32 KiB, 2 KiB of it are left for the indexation payload,
+/// the Streams header and the signature added around the published data
+fn default_max_message_size() -> usize {
+    30 * 1024
+}
+
+fn default_header_timeout() -> u64 {
+    10
+}
+
+fn default_read_timeout() -> u64 {
+    30
+}
+
+fn default_batch_interval() -> u64 {
+    10
+}
+
+fn default_batch_max_readings() -> usize {
+    100
+}
+
+fn default_batch_max_pending() -> usize {
+    10000
+}
+
+fn default_receipt_ttl() -> u64 {
+    3600
+}
+
+fn default_anchor_interval() -> u64 {
+    60
+}
+
+fn default_anchor_store_path() -> String {
+    String::from("anchors.jsonl")
+}
+
+fn default_anchor_retention() -> u64 {
+    30 * 24 * 3600
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_log_format() -> String {
+    "pretty".to_string()
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "streams-gateway".to_string()
+}
+
+fn default_mqtt_keep_alive() -> u16 {
+    30
+}
+
+fn default_mqtt_reconnect_interval() -> u64 {
+    5
 }
@@ -0,0 +1,15 @@
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+///
+/// Value of a reading encrypted for a tenant, replacing the JSON value it was created from.
+/// The associated data is "{tenant}/{sensor}/{field}", with an empty field if the whole sensor is encrypted
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedValue {
+    pub alg: String,
+    pub tenant: String,
+    pub nonce: String,
+    pub tag: String,
+    pub ciphertext: String,
+}
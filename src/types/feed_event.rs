@@ -0,0 +1,10 @@
+use crate::types::sensor_data::SensorData;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeedEvent {
+    pub status: String,
+    pub channel_id: String,
+    pub data: SensorData,
+}
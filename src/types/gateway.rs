@@ -0,0 +1,28 @@
+use crate::publishing::anchoring::AnchorStore;
+use crate::publishing::batcher::Batch;
+use crate::publishing::channel_history::ChannelHistory;
+use crate::publishing::field_encryption::FieldEncryption;
+use crate::publishing::node_pool::NodePool;
+use crate::publishing::retry::CircuitBreaker;
+use crate::types::channel_state::ChannelState;
+use crate::types::config::RetryConfig;
+use crate::types::feed_event::FeedEvent;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+///
+/// State shared by the servers and the background tasks. The channel state is locked while a message is published,
+/// which includes the proof of work, so everything requests have to answer without publishing is kept outside of it
+///
+pub struct Gateway {
+    pub channel_state: Mutex<ChannelState>,
+    pub history: Mutex<ChannelHistory>,
+    pub batch: Mutex<Batch>,
+    pub anchors: Mutex<AnchorStore>,
+    pub nodes: Arc<Mutex<NodePool>>,
+    pub field_encryption: FieldEncryption,
+    pub retry: RetryConfig,
+    pub breaker: Mutex<CircuitBreaker>,
+    pub dry_run: bool,
+    pub feed: broadcast::Sender<FeedEvent>,
+    pub last_published: Mutex<Option<u64>>,
+}
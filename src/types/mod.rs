@@ -1,6 +1,13 @@
+pub mod anchor;
 pub mod bundle_data;
+pub mod bundle_part;
+pub mod channel_record;
 pub mod channel_state;
 pub mod config;
+pub mod encrypted_value;
+pub mod feed_event;
+pub mod gateway;
+pub mod senml;
 pub mod sensor_data;
 pub mod sensor_type;
 pub mod switch_auth;
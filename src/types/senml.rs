@@ -0,0 +1,403 @@
+use crate::timestamp_in_sec;
+use crate::types::{sensor_data::SensorData, sensor_type::SensorType};
+use anyhow::{anyhow, Result};
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use serde_json::{Map, Value};
+use std::convert::TryFrom;
+
+///
+/// A single SenML record (RFC 8428) using the JSON labels
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SenmlRecord {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bt: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bu: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bv: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub u: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vs: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vb: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub t: Option<f64>,
+}
+
+///
+/// key of the time of a record inside the data of a sensor, the resolved time in seconds since the epoch
+///
+pub const TIME_KEY: &str = "timestamp";
+
+///
+/// times below 2**28 are relative to the current time (RFC 8428 section 4.5.3)
+///
+const RELATIVE_TIME_LIMIT: f64 = 268_435_456.0;
+
+///
+/// Parses a SenML pack encoded as CBOR, translating the integer labels of RFC 8428 section 6 to the JSON ones
+///
+pub fn pack_from_cbor(data: &[u8]) -> Result<Vec<SenmlRecord>> {
+    let value: serde_cbor::Value =
+        serde_cbor::from_slice(data).map_err(|e| anyhow!("Malformed cbor - {}", e))?;
+    let records = match value {
+        serde_cbor::Value::Array(records) => records,
+        _ => return Err(anyhow!("SenML pack must be an array")),
+    };
+
+    let mut pack = vec![];
+    for record in records {
+        let entries = match record {
+            serde_cbor::Value::Map(entries) => entries,
+            _ => return Err(anyhow!("SenML record must be a map")),
+        };
+        let mut json = Map::new();
+        for (label, value) in entries {
+            let key = match label {
+                serde_cbor::Value::Integer(-2) => "bn",
+                serde_cbor::Value::Integer(-3) => "bt",
+                serde_cbor::Value::Integer(-4) => "bu",
+                serde_cbor::Value::Integer(-5) => "bv",
+                serde_cbor::Value::Integer(0) => "n",
+                serde_cbor::Value::Integer(1) => "u",
+                serde_cbor::Value::Integer(2) => "v",
+                serde_cbor::Value::Integer(3) => "vs",
+                serde_cbor::Value::Integer(4) => "vb",
+                serde_cbor::Value::Integer(6) => "t",
+                serde_cbor::Value::Integer(8) => "vd",
+                // unsupported labels (bver, bs, s, ut) are ignored
+                _ => continue,
+            };
+            let value = match value {
+                serde_cbor::Value::Text(text) => Value::from(text),
+                serde_cbor::Value::Float(float) => Value::from(float),
+                serde_cbor::Value::Integer(int) => Value::from(
+                    i64::try_from(int)
+                        .map_err(|_| anyhow!("Integer out of range for SenML label {}", key))?,
+                ),
+                serde_cbor::Value::Bool(b) => Value::from(b),
+                serde_cbor::Value::Bytes(bytes) => Value::from(base64::encode(bytes)),
+                _ => return Err(anyhow!("Unsupported value for SenML label {}", key)),
+            };
+            json.insert(key.to_string(), value);
+        }
+        pack.push(serde_json::from_value(Value::Object(json))?);
+    }
+    Ok(pack)
+}
+
+///
+/// Converts a SenML pack into the iot2tangle format, the base name becomes the device
+/// and every record is added to the SensorType with the same name. The time of a record (base time plus its time)
+/// is kept next to its value as "timestamp"
+///
+pub fn pack_to_sensor_data(pack: &[SenmlRecord]) -> Result<SensorData> {
+    let mut device: Option<String> = None;
+    let mut base_time: Option<f64> = None;
+    let mut base_unit: Option<String> = None;
+    let mut base_value = 0.0;
+    let mut sensors: Vec<SensorType> = vec![];
+
+    for record in pack {
+        if let Some(bn) = &record.bn {
+            let bn = bn
+                .trim_end_matches(|c: char| c == ':' || c == '/')
+                .to_string();
+            match &device {
+                Some(d) if d != &bn => {
+                    return Err(anyhow!("SenML pack must contain a single base name"))
+                }
+                _ => device = Some(bn),
+            }
+        }
+        if record.bt.is_some() {
+            base_time = record.bt;
+        }
+        if record.bu.is_some() {
+            base_unit = record.bu.clone();
+        }
+        if let Some(bv) = record.bv {
+            base_value = bv;
+        }
+
+        let value = if let Some(v) = record.v {
+            Value::from(base_value + v)
+        } else if let Some(vs) = &record.vs {
+            Value::from(vs.clone())
+        } else if let Some(vb) = record.vb {
+            Value::from(vb)
+        } else if let Some(vd) = &record.vd {
+            Value::from(vd.clone())
+        } else {
+            continue;
+        };
+
+        let name = record.n.clone().unwrap_or_default();
+        let unit = record
+            .u
+            .clone()
+            .or_else(|| base_unit.clone())
+            .unwrap_or_else(|| "v".to_string());
+        let mut entry = Map::new();
+        entry.insert(unit, value);
+        if record.t.is_some() {
+            let time = base_time.unwrap_or(0.0) + record.t.unwrap_or(0.0);
+            entry.insert(TIME_KEY.to_string(), Value::from(resolve_time(time)));
+        }
+
+        match sensors.iter_mut().find(|s| s.sensor == name) {
+            Some(sensor) => sensor.data.push(Value::Object(entry)),
+            None => sensors.push(SensorType {
+                sensor: name,
+                data: vec![Value::Object(entry)],
+            }),
+        }
+    }
+
+    Ok(SensorData {
+        iot2tangle: sensors,
+        device: device.ok_or_else(|| anyhow!("SenML pack has no base name"))?,
+        timestamp: base_time.map(Value::from).unwrap_or_else(|| Value::from(0)),
+    })
+}
+
+///
+/// Converts data in the iot2tangle format into a SenML pack, using the device as base name
+/// and the keys inside the sensor data as units. The time of an entry is stored relative to the base time
+///
+pub fn sensor_data_to_pack(sensor_data: &SensorData) -> Vec<SenmlRecord> {
+    let base_time = sensor_data.timestamp.as_f64();
+    let mut pack: Vec<SenmlRecord> = sensor_data
+        .iot2tangle
+        .iter()
+        .flat_map(|sensor| sensor_records(&sensor.sensor, &sensor.data))
+        .collect();
+
+    if pack.is_empty() {
+        pack.push(SenmlRecord::default());
+    }
+    for record in pack.iter_mut() {
+        record.t = record.t.map(|t| t - base_time.unwrap_or(0.0));
+    }
+    pack[0].bn = Some(sensor_data.device.clone());
+    pack[0].bt = base_time;
+    pack
+}
+
+///
+/// Records of the data of a sensor, one for every key of its entries. The "timestamp" of an entry
+/// becomes the absolute time of its records
+///
+pub fn sensor_records(sensor: &str, data: &[Value]) -> Vec<SenmlRecord> {
+    let mut records = vec![];
+    for entry in data {
+        let entries = match entry {
+            Value::Object(entries) => entries,
+            _ => continue,
+        };
+        let time = entries.get(TIME_KEY).and_then(Value::as_f64);
+        for (key, value) in entries {
+            if key == TIME_KEY && time.is_some() {
+                continue;
+            }
+            let mut record = SenmlRecord {
+                n: Some(sensor.to_string()),
+                u: Some(key.clone()),
+                t: time,
+                ..Default::default()
+            };
+            set_value(&mut record, value);
+            records.push(record);
+        }
+    }
+    records
+}
+
+///
+/// Sets the value of the record, values other than numbers, booleans and strings are stored as JSON in "vs"
+///
+pub fn set_value(record: &mut SenmlRecord, value: &Value) {
+    match value {
+        Value::Number(n) => record.v = n.as_f64(),
+        Value::Bool(b) => record.vb = Some(*b),
+        Value::String(s) => record.vs = Some(s.clone()),
+        other => record.vs = Some(other.to_string()),
+    }
+}
+
+///
+/// resolves a time of a pack, times below 2**28 are seconds relative to now
+///
+fn resolve_time(time: f64) -> f64 {
+    if time < RELATIVE_TIME_LIMIT {
+        timestamp_in_sec() as f64 + time
+    } else {
+        time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_cbor::Value as Cbor;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    fn cbor_pack(records: Vec<Vec<(i128, Cbor)>>) -> Vec<u8> {
+        let records = records
+            .into_iter()
+            .map(|record| {
+                let entries: BTreeMap<Cbor, Cbor> = record
+                    .into_iter()
+                    .map(|(label, value)| (Cbor::Integer(label), value))
+                    .collect();
+                Cbor::Map(entries)
+            })
+            .collect();
+        serde_cbor::to_vec(&Cbor::Array(records)).unwrap()
+    }
+
+    fn pack(records: serde_json::Value) -> Vec<SenmlRecord> {
+        serde_json::from_value(records).unwrap()
+    }
+
+    #[test]
+    fn cbor_labels_are_translated() {
+        let data = cbor_pack(vec![
+            vec![
+                (-2, Cbor::Text("urn:dev:ow:10e2073a01080063:".into())),
+                (-3, Cbor::Integer(1_320_067_464)),
+                (0, Cbor::Text("temp".into())),
+                (1, Cbor::Text("Cel".into())),
+                (2, Cbor::Float(23.1)),
+                // unsupported labels are skipped
+                (5, Cbor::Text("s".into())),
+            ],
+            vec![
+                (0, Cbor::Text("door".into())),
+                (4, Cbor::Bool(true)),
+                (6, Cbor::Integer(-5)),
+            ],
+        ]);
+        let pack = pack_from_cbor(&data).unwrap();
+        assert_eq!(pack.len(), 2);
+        assert_eq!(pack[0].bn.as_deref(), Some("urn:dev:ow:10e2073a01080063:"));
+        assert_eq!(pack[0].bt, Some(1_320_067_464.0));
+        assert_eq!(pack[0].n.as_deref(), Some("temp"));
+        assert_eq!(pack[0].u.as_deref(), Some("Cel"));
+        assert_eq!(pack[0].v, Some(23.1));
+        assert_eq!(pack[1].vb, Some(true));
+        assert_eq!(pack[1].t, Some(-5.0));
+    }
+
+    #[test]
+    fn cbor_integers_out_of_range_are_rejected() {
+        let data = cbor_pack(vec![vec![(-3, Cbor::Integer(i128::from(i64::MAX) + 1))]]);
+        assert!(pack_from_cbor(&data).is_err());
+    }
+
+    #[test]
+    fn cbor_pack_must_be_an_array_of_maps() {
+        let data = serde_cbor::to_vec(&Cbor::Integer(1)).unwrap();
+        assert!(pack_from_cbor(&data).is_err());
+        let data = serde_cbor::to_vec(&Cbor::Array(vec![Cbor::Integer(1)])).unwrap();
+        assert!(pack_from_cbor(&data).is_err());
+    }
+
+    #[test]
+    fn base_values_and_times_are_resolved() {
+        let sensor_data = pack_to_sensor_data(&pack(json!([
+            {"bn": "DEVICE_ID_1/", "bt": 1_600_000_000.0, "bu": "Cel", "bv": 20.0, "n": "temp", "v": 1.5},
+            {"n": "temp", "v": 2.0, "t": 10.0},
+            {"n": "hum", "u": "%RH", "v": 40.0, "t": -5.0},
+            {"n": "door", "vb": false},
+            {"n": "empty"}
+        ])))
+        .unwrap();
+        assert_eq!(sensor_data.device, "DEVICE_ID_1");
+        assert_eq!(sensor_data.timestamp, json!(1_600_000_000.0));
+        let sensors = serde_json::to_value(&sensor_data.iot2tangle).unwrap();
+        assert_eq!(
+            sensors,
+            json!([
+                {"sensor": "temp", "data": [{"Cel": 21.5}, {"Cel": 22.0, "timestamp": 1_600_000_010.0}]},
+                {"sensor": "hum", "data": [{"%RH": 60.0, "timestamp": 1_599_999_995.0}]},
+                {"sensor": "door", "data": [{"Cel": false}]}
+            ])
+        );
+    }
+
+    #[test]
+    fn small_times_are_relative_to_now() {
+        let sensor_data = pack_to_sensor_data(&pack(json!([
+            {"bn": "DEVICE_ID_1", "n": "temp", "u": "Cel", "v": 20.0, "t": -60.0}
+        ])))
+        .unwrap();
+        let time = sensor_data.iot2tangle[0].data[0][TIME_KEY]
+            .as_f64()
+            .unwrap();
+        let expected = timestamp_in_sec() as f64 - 60.0;
+        assert!((time - expected).abs() <= 2.0);
+    }
+
+    #[test]
+    fn packs_need_a_single_base_name() {
+        assert!(pack_to_sensor_data(&pack(json!([{"n": "temp", "v": 1.0}]))).is_err());
+        assert!(pack_to_sensor_data(&pack(json!([
+            {"bn": "DEVICE_ID_1", "n": "temp", "v": 1.0},
+            {"bn": "DEVICE_ID_2", "n": "temp", "v": 2.0}
+        ])))
+        .is_err());
+    }
+
+    #[test]
+    fn sensor_data_round_trips() {
+        let sensor_data: SensorData = serde_json::from_value(json!({
+            "iot2tangle": [
+                {"sensor": "Gyroscope", "data": [{"x": 4514.0}, {"y": 244.0, "timestamp": 1_600_000_030.0}]},
+                {"sensor": "Label", "data": [{"name": "front door"}, {"open": true}]}
+            ],
+            "device": "DEVICE_ID_1",
+            "timestamp": 1_600_000_000.0
+        }))
+        .unwrap();
+
+        let pack = sensor_data_to_pack(&sensor_data);
+        assert_eq!(pack.len(), 4);
+        assert_eq!(pack[0].bn.as_deref(), Some("DEVICE_ID_1"));
+        assert_eq!(pack[0].bt, Some(1_600_000_000.0));
+        assert_eq!(pack[0].t, None);
+        // times are relative to the base time
+        assert_eq!(pack[1].t, Some(30.0));
+        assert!(pack[1..].iter().all(|record| record.bn.is_none()));
+
+        let restored = pack_to_sensor_data(&pack).unwrap();
+        assert_eq!(
+            serde_json::to_value(&restored).unwrap(),
+            serde_json::to_value(&sensor_data).unwrap()
+        );
+    }
+
+    #[test]
+    fn empty_sensor_data_keeps_the_base_name() {
+        let sensor_data = SensorData {
+            iot2tangle: vec![],
+            device: "DEVICE_ID_1".to_string(),
+            timestamp: json!(1_600_000_000),
+        };
+        let pack = sensor_data_to_pack(&sensor_data);
+        assert_eq!(pack.len(), 1);
+        assert_eq!(pack[0].bn.as_deref(), Some("DEVICE_ID_1"));
+    }
+}
@@ -2,7 +2,7 @@ use crate::types::sensor_type::SensorType;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SensorData {
     pub iot2tangle: Vec<SensorType>,
     pub device: String,
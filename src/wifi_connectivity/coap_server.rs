@@ -0,0 +1,1080 @@
+use crate::device_auth::keystore::KeyManager;
+use crate::monitoring::{logging, metrics};
+use crate::shutdown;
+use crate::types::{config::Config, gateway::Gateway};
+use crate::wifi_connectivity::handlers::*;
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::net::udp::SendHalf;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{info, info_span, warn, Instrument};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+const CON: u8 = 0;
+const NON: u8 = 1;
+const ACK: u8 = 2;
+const RST: u8 = 3;
+
+const GET: u8 = 0x01;
+const POST: u8 = 0x02;
+const CHANGED: u8 = 0x44;
+const CONTENT: u8 = 0x45;
+const CONTINUE: u8 = 0x5F;
+const BAD_REQUEST: u8 = 0x80;
+const UNAUTHORIZED: u8 = 0x81;
+const NOT_FOUND: u8 = 0x84;
+const METHOD_NOT_ALLOWED: u8 = 0x85;
+const REQUEST_ENTITY_INCOMPLETE: u8 = 0x88;
+const REQUEST_ENTITY_TOO_LARGE: u8 = 0x8D;
+const INTERNAL_SERVER_ERROR: u8 = 0xA0;
+const SERVICE_UNAVAILABLE: u8 = 0xA3;
+const GATEWAY_TIMEOUT: u8 = 0xA4;
+
+/// Content-Format of text/plain;charset=utf-8 and application/json
+const TEXT_PLAIN: u16 = 0;
+const APPLICATION_JSON: u16 = 50;
+
+const URI_PATH: u16 = 11;
+const CONTENT_FORMAT: u16 = 12;
+const URI_QUERY: u16 = 15;
+const BLOCK2: u16 = 23;
+const BLOCK1: u16 = 27;
+const SIZE1: u16 = 60;
+
+/// size exponent of the blocks used for responses if the client does not ask for a smaller one (1024 bytes)
+const MAX_SZX: u8 = 6;
+/// time a response to a confirmable message is kept to answer retransmissions (EXCHANGE_LIFETIME)
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+/// cached responses, block-wise uploads and downloads kept at most, each
+const MAX_EXCHANGES: usize = 1024;
+/// requests handled at the same time, further requests are answered with 5.03
+const MAX_IN_FLIGHT: usize = 64;
+/// time a confirmable request may take to be answered with a piggybacked response, after that it is acknowledged
+/// with an empty ACK and the response is sent separately
+const PIGGYBACK_TIMEOUT: Duration = Duration::from_secs(1);
+/// initial timeout and number of retransmissions of a separate response (RFC 7252 section 4.8)
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: u32 = 4;
+
+struct Message {
+    msg_type: u8,
+    code: u8,
+    message_id: u16,
+    token: Vec<u8>,
+    options: Vec<(u16, Vec<u8>)>,
+    payload: Vec<u8>,
+}
+
+impl Message {
+    fn parse(buf: &[u8]) -> Option<Message> {
+        if buf.len() < 4 || buf[0] >> 6 != 1 {
+            return None;
+        }
+        let token_len = (buf[0] & 0x0F) as usize;
+        if token_len > 8 || buf.len() < 4 + token_len {
+            return None;
+        }
+        let mut message = Message {
+            msg_type: (buf[0] >> 4) & 0x03,
+            code: buf[1],
+            message_id: u16::from_be_bytes([buf[2], buf[3]]),
+            token: buf[4..4 + token_len].to_vec(),
+            options: vec![],
+            payload: vec![],
+        };
+
+        let mut pos = 4 + token_len;
+        let mut number: u16 = 0;
+        while pos < buf.len() {
+            if buf[pos] == 0xFF {
+                message.payload = buf[pos + 1..].to_vec();
+                break;
+            }
+            let header = buf[pos];
+            let delta = read_option_nibble(buf, &mut pos, header >> 4)?;
+            let len = read_option_nibble(buf, &mut pos, header & 0x0F)? as usize;
+            pos += 1;
+            if buf.len() < pos + len {
+                return None;
+            }
+            number = number.checked_add(delta)?;
+            message.options.push((number, buf[pos..pos + len].to_vec()));
+            pos += len;
+        }
+        Some(message)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![
+            0x40 | (self.msg_type << 4) | self.token.len() as u8,
+            self.code,
+        ];
+        buf.extend(&self.message_id.to_be_bytes());
+        buf.extend(&self.token);
+
+        let mut options = self.options.clone();
+        options.sort_by_key(|(number, _)| *number);
+        let mut last = 0;
+        for (number, value) in options {
+            let (delta, delta_ext) = option_nibble(number - last);
+            let (len, len_ext) = option_nibble(value.len() as u16);
+            buf.push((delta << 4) | len);
+            buf.extend(delta_ext);
+            buf.extend(len_ext);
+            buf.extend(value);
+            last = number;
+        }
+
+        if !self.payload.is_empty() {
+            buf.push(0xFF);
+            buf.extend(&self.payload);
+        }
+        buf
+    }
+
+    fn option_values(&self, number: u16) -> Vec<String> {
+        self.options
+            .iter()
+            .filter(|(n, _)| *n == number)
+            .map(|(_, value)| String::from_utf8_lossy(value).to_string())
+            .collect()
+    }
+
+    fn block(&self, number: u16) -> Option<Block> {
+        self.options
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, value)| Block::decode(value))
+    }
+}
+
+///
+/// reads the (possibly extended) option delta or length encoded in the given nibble
+///
+fn read_option_nibble(buf: &[u8], pos: &mut usize, nibble: u8) -> Option<u16> {
+    match nibble {
+        13 => {
+            let value = *buf.get(*pos + 1)? as u16 + 13;
+            *pos += 1;
+            Some(value)
+        }
+        14 => {
+            let value = u16::from_be_bytes([*buf.get(*pos + 1)?, *buf.get(*pos + 2)?]);
+            *pos += 2;
+            value.checked_add(269)
+        }
+        15 => None,
+        n => Some(n as u16),
+    }
+}
+
+fn option_nibble(value: u16) -> (u8, Vec<u8>) {
+    if value < 13 {
+        (value as u8, vec![])
+    } else if value < 269 {
+        (13, vec![(value - 13) as u8])
+    } else {
+        (14, (value - 269).to_be_bytes().to_vec())
+    }
+}
+
+///
+/// value of a Block1 or Block2 option (RFC 7959)
+///
+#[derive(Clone, Copy)]
+struct Block {
+    num: u32,
+    more: bool,
+    szx: u8,
+}
+
+impl Block {
+    fn decode(value: &[u8]) -> Block {
+        let mut raw: u32 = 0;
+        for byte in value {
+            raw = (raw << 8) | *byte as u32;
+        }
+        Block {
+            num: raw >> 4,
+            more: raw & 0x08 != 0,
+            szx: (raw & 0x07) as u8,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let raw = (self.num << 4) | ((self.more as u32) << 3) | self.szx as u32;
+        let bytes = raw.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count().min(3);
+        bytes[skip..].to_vec()
+    }
+
+    fn size(&self) -> usize {
+        1 << (self.szx + 4)
+    }
+}
+
+///
+/// response kept for the follow-up requests of its blocks
+///
+struct Download {
+    used: Instant,
+    code: u8,
+    format: u16,
+    body: Vec<u8>,
+}
+
+///
+/// exchanges with clients that span multiple datagrams, with the time they were last used.
+/// Each kind is limited to MAX_EXCHANGES entries and dropped after EXCHANGE_LIFETIME
+///
+#[derive(Default)]
+struct Exchanges {
+    responses: HashMap<(SocketAddr, u16), (Instant, Vec<u8>)>,
+    uploads: HashMap<(SocketAddr, String), (Instant, Vec<u8>)>,
+    downloads: HashMap<(SocketAddr, String), Download>,
+    /// requests being handled, retransmissions of them are ignored until they are acknowledged
+    in_flight: HashSet<(SocketAddr, u16)>,
+    /// separate responses sent as confirmable messages and not yet acknowledged by the client
+    unacknowledged: HashSet<(SocketAddr, u16)>,
+    message_id: u16,
+}
+
+impl Exchanges {
+    fn new() -> Exchanges {
+        Exchanges {
+            message_id: rand::random(),
+            ..Exchanges::default()
+        }
+    }
+
+    fn expire(&mut self) {
+        self.responses
+            .retain(|_, (used, _)| used.elapsed() < EXCHANGE_LIFETIME);
+        self.uploads
+            .retain(|_, (used, _)| used.elapsed() < EXCHANGE_LIFETIME);
+        self.downloads
+            .retain(|_, download| download.used.elapsed() < EXCHANGE_LIFETIME);
+    }
+
+    ///
+    /// message id of a message sent by the server, other than a piggybacked response
+    ///
+    fn next_message_id(&mut self) -> u16 {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.message_id
+    }
+
+    ///
+    /// caches the response to a confirmable request, replacing the oldest one if the cache is full
+    ///
+    fn cache_response(&mut self, key: (SocketAddr, u16), bytes: Vec<u8>) {
+        if self.responses.len() >= MAX_EXCHANGES && !self.responses.contains_key(&key) {
+            let oldest = self
+                .responses
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.responses.remove(&oldest);
+            }
+        }
+        self.responses.insert(key, (Instant::now(), bytes));
+    }
+
+    ///
+    /// keeps the response for the follow-up requests of its blocks, replacing the oldest one if all are in use
+    ///
+    fn store_download(&mut self, key: (SocketAddr, String), code: u8, format: u16, body: Vec<u8>) {
+        if self.downloads.len() >= MAX_EXCHANGES && !self.downloads.contains_key(&key) {
+            let oldest = self
+                .downloads
+                .iter()
+                .min_by_key(|(_, download)| download.used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.downloads.remove(&oldest);
+            }
+        }
+        let download = Download {
+            used: Instant::now(),
+            code: code,
+            format: format,
+            body: body,
+        };
+        self.downloads.insert(key, download);
+    }
+}
+
+///
+/// Starts a CoAP server on the provided UDP port, offering the /sensor_data, /bundle_data and /current_channel resources
+/// which are handed over to the same handler functions used by the http server.
+/// Confirmable requests are acknowledged with a piggybacked response, or with an empty ACK followed by a separate
+/// confirmable response if publishing takes longer, retransmissions are answered from a cache.
+/// Payloads larger than a single datagram can be transferred block-wise in both directions.
+/// The server stops receiving once the shutdown is requested
+///
+pub async fn start(
+    config: Config,
+    gateway: Arc<Gateway>,
+    keystore: Arc<Mutex<KeyManager>>,
+    port: u16,
+) -> Result<()> {
+    let ip: IpAddr = config.bind_address.parse()?;
+    let addr = SocketAddr::new(ip, port);
+    let (mut receiver, mut sender) = UdpSocket::bind(&addr).await?.split();
+    let exchanges = Arc::new(Mutex::new(Exchanges::new()));
+    // responses of the requests handled on their own tasks, sent from the receive loop
+    let (responses, mut outgoing) = mpsc::channel::<(Vec<u8>, SocketAddr)>(MAX_IN_FLIGHT);
+
+    info!("Listening on coap://{}", addr);
+
+    let mut buf = [0; 2048];
+    loop {
+        let (len, peer) = tokio::select! {
+            received = receiver.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!(error = %e, "could not receive CoAP datagram");
+                    continue;
+                }
+            },
+            Some((bytes, peer)) = outgoing.recv() => {
+                send(&mut sender, &bytes, peer).await;
+                continue;
+            }
+            _ = shutdown::requested() => {
+                info!("CoAP server stopped");
+                return Ok(());
+            }
+        };
+        let request = match Message::parse(&buf[..len]) {
+            Some(request) => request,
+            None => continue,
+        };
+
+        if request.msg_type == ACK || request.msg_type == RST {
+            // the client received a separate response
+            exchanges
+                .lock()
+                .unwrap()
+                .unacknowledged
+                .remove(&(peer, request.message_id));
+            continue;
+        }
+        if request.code == 0 {
+            // CoAP ping
+            let reset = Message {
+                msg_type: RST,
+                code: 0,
+                message_id: request.message_id,
+                token: vec![],
+                options: vec![],
+                payload: vec![],
+            };
+            send(&mut sender, &reset.to_bytes(), peer).await;
+            continue;
+        }
+
+        let exchange = (peer, request.message_id);
+        let (cached, busy) = {
+            let mut exchanges = exchanges.lock().unwrap();
+            exchanges.expire();
+            let cached = match request.msg_type {
+                CON => exchanges
+                    .responses
+                    .get(&exchange)
+                    .map(|(_, cached)| cached.clone()),
+                _ => None,
+            };
+            if cached.is_some() || exchanges.in_flight.contains(&exchange) {
+                (cached, None)
+            } else if exchanges.in_flight.len() >= MAX_IN_FLIGHT {
+                (None, Some(true))
+            } else {
+                exchanges.in_flight.insert(exchange);
+                (None, Some(false))
+            }
+        };
+
+        match (cached, busy) {
+            (Some(cached), _) => send(&mut sender, &cached, peer).await,
+            // a retransmission of a request which is answered within PIGGYBACK_TIMEOUT
+            (None, None) => {}
+            (None, Some(true)) => {
+                warn!("too many CoAP requests in flight");
+                let response = reply(SERVICE_UNAVAILABLE, "Too many requests");
+                let response = piggyback(response, &request, &exchanges);
+                send(&mut sender, &response.to_bytes(), peer).await;
+            }
+            (None, Some(false)) => {
+                // handled on its own task, so publishing does not hold up the receive loop
+                let exchanges = exchanges.clone();
+                let responses = responses.clone();
+                let config = config.clone();
+                let gateway = gateway.clone();
+                let keystore = keystore.clone();
+                tokio::spawn(async move {
+                    let handled = handle(&request, peer, &exchanges, &config, &gateway, &keystore);
+                    tokio::pin!(handled);
+                    let response = if request.msg_type == CON {
+                        match tokio::time::timeout(PIGGYBACK_TIMEOUT, &mut handled).await {
+                            Ok(response) => response,
+                            Err(_) => {
+                                separate(&request, handled, peer, &exchanges, responses).await;
+                                return;
+                            }
+                        }
+                    } else {
+                        handled.await
+                    };
+                    let response = piggyback(response, &request, &exchanges);
+                    respond(&request, response, peer, &exchanges, responses).await;
+                });
+            }
+        }
+    }
+}
+
+///
+/// sends the datagram, errors are logged as the server keeps serving the other clients
+///
+async fn send(sender: &mut SendHalf, bytes: &[u8], peer: SocketAddr) {
+    if let Err(e) = sender.send_to(bytes, &peer).await {
+        warn!(error = %e, peer = %peer, "could not send CoAP response");
+    }
+}
+
+///
+/// sets the token, type and message id of the response to the request, the ACK of a confirmable request
+/// carries the response and a non-confirmable one is answered with a new message
+///
+fn piggyback(mut response: Message, request: &Message, exchanges: &Mutex<Exchanges>) -> Message {
+    response.token = request.token.clone();
+    if request.msg_type == CON {
+        response.msg_type = ACK;
+        response.message_id = request.message_id;
+    } else {
+        response.msg_type = NON;
+        response.message_id = exchanges.lock().unwrap().next_message_id();
+    }
+    response
+}
+
+///
+/// acknowledges a confirmable request which takes longer than PIGGYBACK_TIMEOUT with an empty ACK,
+/// which also answers its retransmissions, and sends the response once it is ready as confirmable message
+/// (RFC 7252 section 5.2.2)
+///
+async fn separate<F: Future<Output = Message>>(
+    request: &Message,
+    handled: F,
+    peer: SocketAddr,
+    exchanges: &Mutex<Exchanges>,
+    mut responses: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+) {
+    let ack = Message {
+        msg_type: ACK,
+        code: 0,
+        message_id: request.message_id,
+        token: vec![],
+        options: vec![],
+        payload: vec![],
+    }
+    .to_bytes();
+    exchanges
+        .lock()
+        .unwrap()
+        .cache_response((peer, request.message_id), ack.clone());
+    let _ = responses.send((ack, peer)).await;
+
+    let mut response = handled.await;
+    response.token = request.token.clone();
+    response.msg_type = CON;
+    response.message_id = {
+        let mut exchanges = exchanges.lock().unwrap();
+        exchanges.in_flight.remove(&(peer, request.message_id));
+        exchanges.next_message_id()
+    };
+    deliver(&response, peer, exchanges, responses).await;
+}
+
+///
+/// sends a confirmable response until the client acknowledges it, retransmitting it with exponential back-off
+/// up to MAX_RETRANSMIT times (RFC 7252 section 4.2)
+///
+async fn deliver(
+    response: &Message,
+    peer: SocketAddr,
+    exchanges: &Mutex<Exchanges>,
+    mut responses: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+) {
+    let key = (peer, response.message_id);
+    let bytes = response.to_bytes();
+    exchanges.lock().unwrap().unacknowledged.insert(key);
+    let mut timeout = ACK_TIMEOUT.mul_f64(1.0 + rand::random::<f64>() / 2.0);
+    for _ in 0..=MAX_RETRANSMIT {
+        // the receive loop is gone once the shutdown was requested
+        if responses.send((bytes.clone(), peer)).await.is_err() {
+            break;
+        }
+        tokio::time::delay_for(timeout).await;
+        if !exchanges.lock().unwrap().unacknowledged.contains(&key) {
+            return;
+        }
+        timeout *= 2;
+    }
+    exchanges.lock().unwrap().unacknowledged.remove(&key);
+    warn!(peer = %peer, "separate CoAP response was not acknowledged");
+}
+
+///
+/// caches the response to a confirmable request and hands it over to the receive loop
+///
+async fn respond(
+    request: &Message,
+    response: Message,
+    peer: SocketAddr,
+    exchanges: &Mutex<Exchanges>,
+    mut responses: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+) {
+    let bytes = response.to_bytes();
+    {
+        let mut exchanges = exchanges.lock().unwrap();
+        exchanges.in_flight.remove(&(peer, request.message_id));
+        if request.msg_type == CON {
+            exchanges.cache_response((peer, request.message_id), bytes.clone());
+        }
+    }
+    // the receive loop is gone once the shutdown was requested
+    let _ = responses.send((bytes, peer)).await;
+}
+
+async fn handle(
+    request: &Message,
+    peer: SocketAddr,
+    exchanges: &Mutex<Exchanges>,
+    config: &Config,
+    gateway: &Arc<Gateway>,
+    keystore: &Arc<Mutex<KeyManager>>,
+) -> Message {
+    let path = format!("/{}", request.option_values(URI_PATH).join("/"));
+    let query = request.option_values(URI_QUERY).join("&");
+    let key = (peer, path.clone());
+
+    // follow-up requests for the remaining blocks of a response
+    if let Some(block2) = request.block(BLOCK2) {
+        if block2.num > 0 {
+            let mut exchanges = exchanges.lock().unwrap();
+            return match exchanges.downloads.get_mut(&key) {
+                Some(download) => {
+                    download.used = Instant::now();
+                    response_block(download.code, download.format, &download.body, block2)
+                }
+                None => reply(REQUEST_ENTITY_INCOMPLETE, "No response to continue"),
+            };
+        }
+    }
+
+    let mut payload = request.payload.clone();
+    if let Some(block1) = request.block(BLOCK1) {
+        let limit = if path == "/bundle_data" {
+            config.limits.max_bundle_size
+        } else {
+            config.limits.max_body_size
+        };
+        let mut exchanges = exchanges.lock().unwrap();
+        if !exchanges.uploads.contains_key(&key) && exchanges.uploads.len() >= MAX_EXCHANGES {
+            warn!("too many block-wise CoAP uploads");
+            return reply(SERVICE_UNAVAILABLE, "Too many uploads");
+        }
+        let (used, upload) = exchanges
+            .uploads
+            .entry(key.clone())
+            .or_insert_with(|| (Instant::now(), Vec::new()));
+        *used = Instant::now();
+        if block1.num == 0 {
+            upload.clear();
+        }
+        if upload.len() != block1.num as usize * block1.size() {
+            exchanges.uploads.remove(&key);
+            return reply(REQUEST_ENTITY_INCOMPLETE, "Missing block");
+        }
+        if upload.len() + request.payload.len() > limit {
+            exchanges.uploads.remove(&key);
+            warn!(limit, "block-wise CoAP upload too large");
+            let mut response = reply(REQUEST_ENTITY_TOO_LARGE, "Request Entity Too Large");
+            response.options.push((SIZE1, encode_uint(limit as u32)));
+            return response;
+        }
+        upload.extend(&request.payload);
+        if block1.more {
+            let mut response = reply(CONTINUE, "");
+            response.options.push((BLOCK1, block1.encode()));
+            return response;
+        }
+        payload = exchanges
+            .uploads
+            .remove(&key)
+            .map(|(_, upload)| upload)
+            .unwrap_or_default();
+    }
+
+    let uri = if query.is_empty() {
+        path.clone()
+    } else {
+        format!("{}?{}", path, query)
+    };
+    let method = match request.code {
+        GET => Method::GET,
+        POST => Method::POST,
+        _ => return reply(METHOD_NOT_ALLOWED, "Method Not Allowed"),
+    };
+    let req = match Request::builder()
+        .method(method.clone())
+        .uri(uri)
+        .body(Body::from(payload))
+    {
+        Ok(req) => req,
+        Err(_) => return reply(BAD_REQUEST, "Malformed Uri"),
+    };
+
+    let config = config.clone();
+    let gateway = gateway.clone();
+    let keystore = keystore.clone();
+    let span = info_span!(
+        "request",
+        request_id = %logging::request_id(),
+        protocol = "coap",
+        method = %method,
+        route = %path,
+        message_id = request.message_id
+    );
+    let result: Result<Response<Body>> = match (&method, path.as_str()) {
+        (&Method::POST, "/sensor_data") => {
+            sensor_data_response(req, gateway, keystore, config)
+                .instrument(span.clone())
+                .await
+        }
+        (&Method::POST, "/bundle_data") => {
+            send_bundle_response(req, gateway, keystore, config)
+                .instrument(span.clone())
+                .await
+        }
+        (&Method::GET, "/current_channel") => {
+            get_current_channel(req, gateway, keystore, config)
+                .instrument(span.clone())
+                .await
+        }
+        _ => return reply(NOT_FOUND, "Not Found"),
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(_) => return reply(INTERNAL_SERVER_ERROR, "Internal Server Error"),
+    };
+    metrics::record_request("coap", &path, response.status());
+    span.in_scope(|| info!(status = %response.status(), "request handled"));
+
+    let code = coap_code(response.status(), request.code);
+    let body = match hyper::body::to_bytes(response.into_body()).await {
+        Ok(body) => body.to_vec(),
+        Err(_) => return reply(INTERNAL_SERVER_ERROR, "Internal Server Error"),
+    };
+
+    let szx = request
+        .block(BLOCK2)
+        .map(|b| b.szx.min(MAX_SZX))
+        .unwrap_or(MAX_SZX);
+    let first = Block {
+        num: 0,
+        more: false,
+        szx,
+    };
+    let format = content_format(&body);
+    let mut response = if body.len() > first.size() {
+        let response = response_block(code, format, &body, first);
+        exchanges
+            .lock()
+            .unwrap()
+            .store_download(key, code, format, body);
+        response
+    } else {
+        exchanges.lock().unwrap().downloads.remove(&key);
+        let mut response = reply(code, "");
+        if !body.is_empty() {
+            response
+                .options
+                .push((CONTENT_FORMAT, encode_uint(u32::from(format))));
+        }
+        response.payload = body;
+        response
+    };
+    // the response to the last block of a block-wise upload echoes its Block1 option
+    if let Some(block1) = request.block(BLOCK1) {
+        response.options.push((BLOCK1, block1.encode()));
+    }
+    response
+}
+
+///
+/// unsigned integer option value without leading zero bytes
+///
+fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+///
+/// Content-Format of a response body, the handlers answer with JSON or plain text
+///
+fn content_format(body: &[u8]) -> u16 {
+    if serde_json::from_slice::<serde_json::Value>(body).is_ok() {
+        APPLICATION_JSON
+    } else {
+        TEXT_PLAIN
+    }
+}
+
+fn reply(code: u8, payload: &str) -> Message {
+    let mut options = vec![];
+    if !payload.is_empty() {
+        options.push((CONTENT_FORMAT, encode_uint(u32::from(TEXT_PLAIN))));
+    }
+    Message {
+        msg_type: NON,
+        code,
+        message_id: 0,
+        token: vec![],
+        options,
+        payload: payload.as_bytes().to_vec(),
+    }
+}
+
+fn response_block(code: u8, format: u16, body: &[u8], block: Block) -> Message {
+    let size = block.size();
+    let start = (block.num as usize * size).min(body.len());
+    let end = (start + size).min(body.len());
+    let block = Block {
+        more: end < body.len(),
+        ..block
+    };
+    Message {
+        msg_type: NON,
+        code,
+        message_id: 0,
+        token: vec![],
+        options: vec![
+            (CONTENT_FORMAT, encode_uint(u32::from(format))),
+            (BLOCK2, block.encode()),
+        ],
+        payload: body[start..end].to_vec(),
+    }
+}
+
+///
+/// maps the status of the http handlers to the corresponding CoAP response code
+///
+fn coap_code(status: StatusCode, method: u8) -> u8 {
+    match status {
+        StatusCode::OK if method == GET => CONTENT,
+        // a partly published request is reported in the body
+        StatusCode::OK | StatusCode::ACCEPTED | StatusCode::MULTI_STATUS => CHANGED,
+        StatusCode::BAD_REQUEST => BAD_REQUEST,
+        StatusCode::UNAUTHORIZED => UNAUTHORIZED,
+        StatusCode::NOT_FOUND => NOT_FOUND,
+        StatusCode::PAYLOAD_TOO_LARGE => REQUEST_ENTITY_TOO_LARGE,
+        StatusCode::REQUEST_TIMEOUT => GATEWAY_TIMEOUT,
+        StatusCode::SERVICE_UNAVAILABLE => SERVICE_UNAVAILABLE,
+        s if s.is_client_error() => BAD_REQUEST,
+        _ => INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:5683".parse().unwrap()
+    }
+
+    #[test]
+    fn message_is_parsed() {
+        // CON POST, token 0xAB 0xCD, Uri-Path "sensor_data", Content-Format 50, payload "{}"
+        let mut buf = vec![0x42, POST, 0x12, 0x34, 0xAB, 0xCD];
+        buf.push(0xBB);
+        buf.extend(b"sensor_data");
+        buf.extend(&[0x11, 50]);
+        buf.push(0xFF);
+        buf.extend(b"{}");
+
+        let message = Message::parse(&buf).unwrap();
+        assert_eq!(message.msg_type, CON);
+        assert_eq!(message.code, POST);
+        assert_eq!(message.message_id, 0x1234);
+        assert_eq!(message.token, vec![0xAB, 0xCD]);
+        assert_eq!(
+            message.options,
+            vec![
+                (URI_PATH, b"sensor_data".to_vec()),
+                (CONTENT_FORMAT, vec![50])
+            ]
+        );
+        assert_eq!(message.payload, b"{}".to_vec());
+        assert_eq!(message.to_bytes(), buf);
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        // too short, wrong version, token longer than 8 bytes, token missing
+        assert!(Message::parse(&[0x40, GET, 0]).is_none());
+        assert!(Message::parse(&[0x80, GET, 0, 1]).is_none());
+        assert!(Message::parse(&[0x49, GET, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_none());
+        assert!(Message::parse(&[0x42, GET, 0, 1, 0xAB]).is_none());
+        // option longer than the datagram
+        assert!(Message::parse(&[0x40, GET, 0, 1, 0xB5, b'a']).is_none());
+        // reserved nibble 15 in the delta and the length
+        assert!(Message::parse(&[0x40, GET, 0, 1, 0xF0]).is_none());
+        assert!(Message::parse(&[0x40, GET, 0, 1, 0x1F]).is_none());
+        // extended delta without its byte
+        assert!(Message::parse(&[0x40, GET, 0, 1, 0xD0]).is_none());
+        assert!(Message::parse(&[0x40, GET, 0, 1, 0xE0, 0x01]).is_none());
+    }
+
+    #[test]
+    fn option_nibbles_are_extended() {
+        assert_eq!(option_nibble(12), (12, vec![]));
+        assert_eq!(option_nibble(13), (13, vec![0]));
+        assert_eq!(option_nibble(268), (13, vec![255]));
+        assert_eq!(option_nibble(269), (14, vec![0, 0]));
+        assert_eq!(option_nibble(1000), (14, vec![0x02, 0xDB]));
+
+        let buf = [0xD0, 47];
+        let mut pos = 0;
+        assert_eq!(read_option_nibble(&buf, &mut pos, 13), Some(60));
+        assert_eq!(pos, 1);
+        let buf = [0xE0, 0x02, 0xDB];
+        let mut pos = 0;
+        assert_eq!(read_option_nibble(&buf, &mut pos, 14), Some(1000));
+        assert_eq!(pos, 2);
+        // the extended delta can not exceed the option number range
+        let buf = [0xE0, 0xFF, 0xFF];
+        let mut pos = 0;
+        assert_eq!(read_option_nibble(&buf, &mut pos, 14), None);
+    }
+
+    #[test]
+    fn extended_options_round_trip() {
+        let message = Message {
+            msg_type: NON,
+            code: CONTENT,
+            message_id: 7,
+            token: vec![1],
+            options: vec![
+                // delta 60 and 1040 need one and two extension bytes, the value of 300 bytes as well
+                (SIZE1, encode_uint(70_000)),
+                (1100, vec![b'x'; 300]),
+                (URI_PATH, vec![]),
+            ],
+            payload: vec![],
+        };
+        let parsed = Message::parse(&message.to_bytes()).unwrap();
+        assert_eq!(
+            parsed.options,
+            vec![
+                (URI_PATH, vec![]),
+                (SIZE1, vec![0x01, 0x11, 0x70]),
+                (1100, vec![b'x'; 300])
+            ]
+        );
+        assert!(parsed.payload.is_empty());
+    }
+
+    #[test]
+    fn blocks_are_encoded() {
+        let block = Block {
+            num: 0,
+            more: false,
+            szx: 6,
+        };
+        assert_eq!(block.encode(), vec![0x06]);
+        assert_eq!(block.size(), 1024);
+
+        let block = Block {
+            num: 1,
+            more: true,
+            szx: 2,
+        };
+        assert_eq!(block.encode(), vec![0x1A]);
+        assert_eq!(block.size(), 64);
+
+        let block = Block {
+            num: 4100,
+            more: true,
+            szx: 6,
+        };
+        assert_eq!(block.encode(), vec![0x01, 0x00, 0x4E]);
+        let decoded = Block::decode(&block.encode());
+        assert_eq!((decoded.num, decoded.more, decoded.szx), (4100, true, 6));
+
+        let empty = Block::decode(&[]);
+        assert_eq!((empty.num, empty.more, empty.szx), (0, false, 0));
+    }
+
+    #[test]
+    fn response_blocks_cover_the_body() {
+        let body: Vec<u8> = (0..40).collect();
+        let first = Block {
+            num: 0,
+            more: false,
+            szx: 0,
+        };
+        let response = response_block(CONTENT, APPLICATION_JSON, &body, first);
+        assert_eq!(response.payload, body[..16].to_vec());
+        let block2 = response.block(BLOCK2).unwrap();
+        assert!(block2.more);
+        assert_eq!(
+            response.option_values(CONTENT_FORMAT),
+            vec!["2".to_string()]
+        );
+
+        let last = response_block(
+            CONTENT,
+            APPLICATION_JSON,
+            &body,
+            Block {
+                num: 2,
+                more: false,
+                szx: 0,
+            },
+        );
+        assert_eq!(last.payload, body[32..].to_vec());
+        assert!(!last.block(BLOCK2).unwrap().more);
+    }
+
+    #[test]
+    fn exchanges_expire() {
+        let mut exchanges = Exchanges::new();
+        let expired = Instant::now()
+            .checked_sub(EXCHANGE_LIFETIME + Duration::from_secs(1))
+            .unwrap();
+        exchanges.responses.insert((peer(), 1), (expired, vec![1]));
+        exchanges
+            .responses
+            .insert((peer(), 2), (Instant::now(), vec![2]));
+        exchanges
+            .uploads
+            .insert((peer(), "/bundle_data".to_string()), (expired, vec![]));
+        exchanges.store_download((peer(), "/current_channel".to_string()), CONTENT, 0, vec![]);
+        exchanges
+            .downloads
+            .get_mut(&(peer(), "/current_channel".to_string()))
+            .unwrap()
+            .used = expired;
+
+        exchanges.expire();
+        assert_eq!(exchanges.responses.len(), 1);
+        assert!(exchanges.responses.contains_key(&(peer(), 2)));
+        assert!(exchanges.uploads.is_empty());
+        assert!(exchanges.downloads.is_empty());
+    }
+
+    #[test]
+    fn exchanges_are_capped() {
+        let mut exchanges = Exchanges::new();
+        let oldest = Instant::now().checked_sub(Duration::from_secs(10)).unwrap();
+        exchanges.responses.insert((peer(), 0), (oldest, vec![]));
+        for id in 1..MAX_EXCHANGES as u16 {
+            exchanges.cache_response((peer(), id), vec![]);
+        }
+        assert_eq!(exchanges.responses.len(), MAX_EXCHANGES);
+        // replacing a cached response does not evict another one
+        exchanges.cache_response((peer(), 1), vec![1]);
+        assert!(exchanges.responses.contains_key(&(peer(), 0)));
+        exchanges.cache_response((peer(), MAX_EXCHANGES as u16), vec![]);
+        assert_eq!(exchanges.responses.len(), MAX_EXCHANGES);
+        assert!(!exchanges.responses.contains_key(&(peer(), 0)));
+
+        for i in 0..=MAX_EXCHANGES {
+            exchanges.store_download((peer(), format!("/{}", i)), CONTENT, 0, vec![]);
+        }
+        assert_eq!(exchanges.downloads.len(), MAX_EXCHANGES);
+    }
+
+    #[test]
+    fn status_codes_are_mapped() {
+        assert_eq!(coap_code(StatusCode::OK, GET), CONTENT);
+        assert_eq!(coap_code(StatusCode::OK, POST), CHANGED);
+        assert_eq!(coap_code(StatusCode::ACCEPTED, POST), CHANGED);
+        assert_eq!(coap_code(StatusCode::MULTI_STATUS, POST), CHANGED);
+        assert_eq!(
+            coap_code(StatusCode::PAYLOAD_TOO_LARGE, POST),
+            REQUEST_ENTITY_TOO_LARGE
+        );
+        assert_eq!(
+            coap_code(StatusCode::REQUEST_TIMEOUT, POST),
+            GATEWAY_TIMEOUT
+        );
+        assert_eq!(coap_code(StatusCode::CONFLICT, POST), BAD_REQUEST);
+        assert_eq!(
+            coap_code(StatusCode::INTERNAL_SERVER_ERROR, POST),
+            INTERNAL_SERVER_ERROR
+        );
+
+        assert_eq!(content_format(br#"{"status": "OK"}"#), APPLICATION_JSON);
+        assert_eq!(content_format(b"Unauthorized - Device Name"), TEXT_PLAIN);
+    }
+
+    #[tokio::test]
+    async fn separate_response_is_sent_until_acknowledged() {
+        let exchanges = Mutex::new(Exchanges::new());
+        let (responses, mut outgoing) = mpsc::channel::<(Vec<u8>, SocketAddr)>(4);
+        let request = Message {
+            msg_type: CON,
+            code: POST,
+            message_id: 0x1234,
+            token: vec![9],
+            options: vec![],
+            payload: vec![],
+        };
+        exchanges.lock().unwrap().in_flight.insert((peer(), 0x1234));
+        let handled = async { reply(CHANGED, "") };
+        let acknowledging = async {
+            // the empty ACK is sent at once and answers retransmissions of the request
+            let (ack, _) = outgoing.recv().await.unwrap();
+            assert_eq!(ack, vec![0x60, 0, 0x12, 0x34]);
+            assert_eq!(
+                exchanges.lock().unwrap().responses[&(peer(), 0x1234)].1,
+                ack
+            );
+            let (response, _) = outgoing.recv().await.unwrap();
+            let response = Message::parse(&response).unwrap();
+            assert_eq!((response.msg_type, response.code), (CON, CHANGED));
+            assert_eq!(response.token, vec![9]);
+            assert_ne!(response.message_id, 0x1234);
+            exchanges
+                .lock()
+                .unwrap()
+                .unacknowledged
+                .remove(&(peer(), response.message_id));
+        };
+        tokio::join!(
+            separate(&request, handled, peer(), &exchanges, responses),
+            acknowledging
+        );
+        // acknowledged, so it was not retransmitted
+        assert!(outgoing.try_recv().is_err());
+        let exchanges = exchanges.lock().unwrap();
+        assert!(exchanges.in_flight.is_empty());
+        assert!(exchanges.unacknowledged.is_empty());
+    }
+}
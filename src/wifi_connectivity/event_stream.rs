@@ -0,0 +1,109 @@
+use crate::device_auth::keystore::{authenticate, calculate_hash, KeyManager};
+use crate::shutdown;
+use crate::types::{feed_event::FeedEvent, gateway::Gateway, sensor_data::SensorData};
+use crate::wifi_connectivity::handlers::query_param;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::{header, Body, Request, Response, StatusCode};
+use tokio::sync::broadcast::RecvError;
+use tracing::{info, warn};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+/// interval of the comments sent to keep idle connections open
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+///
+/// Pushes the data accepted from a device to the clients connected to /stream
+///
+pub fn notify(gateway: &Gateway, channel_id: &str, sensor_data: &SensorData, published: bool) {
+    let event = FeedEvent {
+        status: if published { "OK" } else { "ERROR" }.to_string(),
+        channel_id: channel_id.to_string(),
+        data: sensor_data.clone(),
+    };
+    // sending only fails if nobody is listening
+    let _ = gateway.feed.send(event);
+}
+
+///
+/// Handles the request for the live feed, the reader is authenticated through the "reader" query parameter.
+/// The data is sent as Server-Sent Events and can be filtered with the "device" and "sensor" query parameters
+///
+pub async fn stream_response(
+    req: Request<Body>,
+    gateway: Arc<Gateway>,
+    keystore: Arc<Mutex<KeyManager>>,
+) -> Result<Response<Body>> {
+    let reader = query_param(req.uri(), "reader");
+    let device = query_param(req.uri(), "device");
+    let sensor = query_param(req.uri(), "sensor");
+
+    let hashes = keystore
+        .lock()
+        .expect("lock keystore")
+        .keystore
+        .api_keys_reader
+        .clone();
+    match reader {
+        Some(reader) if authenticate(&reader, hashes) => {}
+        _ => {
+            warn!("unauthorized request blocked");
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    "Unauthorized - Reader sent doesn't match the configuration",
+                ))?);
+        }
+    }
+    info!("authorized request by reader");
+
+    // devices are published under their hash, the filter accepts both forms
+    let device = device.map(|d| (calculate_hash(d.clone()), d));
+    let mut events = gateway.feed.subscribe();
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+        loop {
+            let chunk = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(mut event) => {
+                        if let Some((hash, name)) = &device {
+                            if &event.data.device != hash && &event.data.device != name {
+                                continue;
+                            }
+                        }
+                        if let Some(sensor) = &sensor {
+                            event.data.iot2tangle.retain(|s| &s.sensor == sensor);
+                            if event.data.iot2tangle.is_empty() {
+                                continue;
+                            }
+                        }
+                        match serde_json::to_string(&event) {
+                            Ok(json) => format!("event: sensor_data\ndata: {}\n\n", json),
+                            Err(_) => continue,
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => format!(": skipped {} events\n\n", skipped),
+                    Err(RecvError::Closed) => break,
+                },
+                _ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+                _ = shutdown::requested() => break,
+            };
+            if sender.send_data(chunk.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)?)
+}
@@ -1,24 +1,278 @@
 use crate::device_auth::keystore::{authenticate, calculate_hash, KeyManager};
+use crate::monitoring::metrics;
 use crate::timestamp_in_sec;
+use crate::types::channel_record::OpenReason;
 use crate::types::{
-    bundle_data::BundleData, channel_state::ChannelState, config::Config, sensor_data::SensorData,
+    bundle_data::BundleData, config::Config, gateway::Gateway, senml, sensor_data::SensorData,
     switch_auth::SwitchAuth,
 };
+use crate::wifi_connectivity::event_stream::notify;
+use crate::wifi_connectivity::limits::{
+    body_error_response, check_message_size, message_too_large_response, read_body,
+};
 
 use std::sync::{Arc, Mutex};
 
-use gateway_core::gateway::publisher::Channel;
+use crate::publishing::anchoring::{self, ProofLookup};
+use crate::publishing::batcher;
+use crate::publishing::publisher;
+use crate::publishing::routing;
+use crate::publishing::splitter::{self, PartialBundle, PublishedBundle};
+use crate::publishing::{retry::CircuitOpen, rotation};
+use serde_json::json;
 
 type GenericError = Box<dyn std::error::Error + Send + Sync>;
 type Result<T> = std::result::Result<T, GenericError>;
 
 use hyper::{header, Body, Request, Response, StatusCode, Uri};
+use tracing::{error, info, info_span, warn};
+
+///
+/// Returns the (percent-decoded) value of a query parameter of the uri
+///
+pub fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    form_urlencoded::parse(uri.query()?.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+///
+/// Handles the status request returning status code 200 if the server is online,
+/// together with the node currently used for publishing, the health of all configured nodes and the circuit breaker
+///
+pub async fn status_response(gateway: Arc<Gateway>) -> Result<Response<Body>> {
+    let breaker = gateway.breaker.lock().unwrap().status();
+    let nodes = gateway.nodes.lock().unwrap();
+    let status = json!({
+        "status": "OK",
+        "active_node": nodes.active,
+        "nodes": nodes.nodes,
+        "circuit_breaker": breaker,
+    });
+    Ok(Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(status.to_string()))?)
+}
+
+///
+/// Builds the response for a message that could not be published, 503 if the circuit breaker is open,
+/// 408 otherwise
+///
+pub fn publish_failed_response(e: &anyhow::Error) -> Result<Response<Body>> {
+    if e.downcast_ref::<CircuitOpen>().is_some() {
+        warn!("publishing rejected, circuit breaker is open");
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                "IOTA Node unavailable after repeated failures, try again later!",
+            ))?);
+    }
+    error!(error = %e, "could not publish to IOTA node");
+    Ok(Response::builder()
+        .status(StatusCode::REQUEST_TIMEOUT)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            "Could not connect to IOTA Node, try with another node!",
+        ))?)
+}
+
+///
+/// Builds the response for data published on the channels selected by the routing rules. Without routing rules the
+/// result of the default channel is returned as before, otherwise the results by channel name, with status code 207
+/// if some of the channels failed. If no channel was published on the error of the first one is returned
+///
+fn routed_response(
+    channels: Vec<(String, anyhow::Result<String>)>,
+    config: &Config,
+) -> Result<Response<Body>> {
+    if channels.iter().all(|(_, result)| result.is_err()) {
+        if let Some((_, Err(e))) = channels.into_iter().next() {
+            return publish_failed_response(&e);
+        }
+        return Err("no channel selected by the routing rules".into());
+    }
+    let failed = channels.iter().any(|(_, result)| result.is_err());
+    let body = if config.routing.is_empty() {
+        match channels.into_iter().next() {
+            Some((_, Ok(channel_id))) => channel_id,
+            _ => return Err("no channel selected by the routing rules".into()),
+        }
+    } else {
+        routing::channel_results(&channels).to_string()
+    };
+    if failed {
+        warn!("data published on some of the channels only");
+    }
+    Ok(Response::builder()
+        .status(if failed {
+            StatusCode::MULTI_STATUS
+        } else {
+            StatusCode::OK
+        })
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))?)
+}
+
+///
+/// Answers a bundle with the channel, the bundle id (null if not split) and the links of its messages,
+/// by channel name with routing rules. A bundle published partly lists the links published before its error,
+/// so the client knows which messages are on the Tangle already
+///
+fn bundle_response(
+    channels: Vec<(String, std::result::Result<PublishedBundle, PartialBundle>)>,
+    config: &Config,
+) -> Result<Response<Body>> {
+    let nothing_published = channels.iter().all(|(_, result)| match result {
+        Ok(_) => false,
+        Err(partial) => partial.published.links.is_empty(),
+    });
+    if nothing_published {
+        if let Some((_, Err(partial))) = channels.into_iter().next() {
+            return publish_failed_response(&partial.error);
+        }
+        return Err("no channel selected by the routing rules".into());
+    }
+    let failed = channels.iter().any(|(_, result)| result.is_err());
+    let results: Vec<(String, serde_json::Value)> = channels
+        .into_iter()
+        .map(|(name, result)| {
+            let result = match result {
+                Ok(published) => bundle_result(&published),
+                Err(partial) => {
+                    let mut result = bundle_result(&partial.published);
+                    result["error"] = json!(partial.error.to_string());
+                    result
+                }
+            };
+            (name, result)
+        })
+        .collect();
+    let body = if config.routing.is_empty() {
+        match results.into_iter().next() {
+            Some((_, result)) => result,
+            None => return Err("no channel selected by the routing rules".into()),
+        }
+    } else {
+        serde_json::Value::Object(results.into_iter().collect())
+    };
+    if failed {
+        warn!("bundle published partly");
+    }
+    Ok(Response::builder()
+        .status(if failed {
+            StatusCode::MULTI_STATUS
+        } else {
+            StatusCode::OK
+        })
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))?)
+}
+
+fn bundle_result(published: &PublishedBundle) -> serde_json::Value {
+    json!({
+        "channel_id": published.channel_id,
+        "bundle_id": published.bundle_id,
+        "messages": published.links,
+    })
+}
+
+///
+/// Adds the reading to the batch and returns its pending receipt with status code 202,
+/// a full batch is published right away instead of waiting for the next interval.
+/// Answers 503 while the batch holds "max_pending" readings
+///
+fn batched_response(
+    sensor_data: SensorData,
+    gateway: Arc<Gateway>,
+    config: Config,
+) -> Result<Response<Body>> {
+    match batcher::accept(&gateway, &config, sensor_data) {
+        Some(receipt) => Ok(Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(&receipt)?))?),
+        None => {
+            warn!("reading rejected, the batch is full");
+            Ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(batcher::BATCH_FULL))?)
+        }
+    }
+}
+
+///
+/// Handles the request for the receipt of a reading accepted in batching mode,
+/// returning its status and, once published, the channel and message ids
+///
+pub async fn receipt_response(req: Request<Body>, gateway: Arc<Gateway>) -> Result<Response<Body>> {
+    let id = req.uri().path().trim_start_matches("/receipts/");
+    let receipt = gateway.batch.lock().unwrap().receipt(id);
+    match receipt {
+        Some(receipt) => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(&receipt)?))?),
+        None => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Unknown receipt"))?),
+    }
+}
 
 ///
-/// Handles the status request returning status code 200 if the server is online
+/// Stores the readings for the next anchoring and returns their ids with status code 202,
+/// a single reading is answered with its "reading_id" and a bundle with the "reading_ids" in its order
 ///
-pub async fn status_response() -> Result<Response<Body>> {
-    Ok(Response::builder().status(200).body(Body::from("OK"))?)
+async fn anchored_response(
+    readings: Vec<SensorData>,
+    gateway: Arc<Gateway>,
+) -> Result<Response<Body>> {
+    let single = readings.len() == 1;
+    match anchoring::store(&gateway, readings).await {
+        Ok(reading_ids) => Ok(Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                if single {
+                    json!({"reading_id": reading_ids[0], "status": "pending"})
+                } else {
+                    json!({"reading_ids": reading_ids, "status": "pending"})
+                }
+                .to_string(),
+            ))?),
+        Err(e) => {
+            error!(error = %e, "could not store reading");
+            Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("Could not store reading"))?)
+        }
+    }
+}
+
+///
+/// Handles the request for the inclusion proof of an anchored reading,
+/// returning status code 202 while the reading waits for the next anchoring
+///
+pub async fn proof_response(req: Request<Body>, gateway: Arc<Gateway>) -> Result<Response<Body>> {
+    let id = req.uri().path().trim_start_matches("/proof/");
+    let lookup = gateway.anchors.lock().unwrap().proof(id);
+    match lookup {
+        ProofLookup::Anchored(proof) => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(&proof)?))?),
+        ProofLookup::Pending => Ok(Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({"reading_id": id, "status": "pending"}).to_string(),
+            ))?),
+        ProofLookup::Unknown => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Unknown reading"))?),
+    }
 }
 
 ///
@@ -28,81 +282,182 @@ pub async fn status_response() -> Result<Response<Body>> {
 ///
 pub async fn sensor_data_response(
     req: Request<Body>,
-    channel_state: Arc<Mutex<ChannelState>>,
+    gateway: Arc<Gateway>,
     keystore: Arc<Mutex<KeyManager>>,
+    config: Config,
 ) -> Result<Response<Body>> {
-    let data = hyper::body::to_bytes(req.into_body()).await?;
+    reading_response(req, ReadingFormat::Json, gateway, keystore, config).await
+}
 
-    let response;
+///
+/// Handles SenML packs (RFC 8428) sent as JSON or CBOR, depending on the Content-Type header.
+/// The base name of the pack is used to authenticate the device, the records are converted into the SensorData Format
+/// and published either as is or as SenML if "publish_senml" is set in the configuration
+///
+pub async fn senml_response(
+    req: Request<Body>,
+    gateway: Arc<Gateway>,
+    keystore: Arc<Mutex<KeyManager>>,
+    config: Config,
+) -> Result<Response<Body>> {
+    let cbor = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.contains("cbor"))
+        .unwrap_or(false);
+    reading_response(
+        req,
+        ReadingFormat::Senml { cbor: cbor },
+        gateway,
+        keystore,
+        config,
+    )
+    .await
+}
 
-    let json_data: serde_json::Result<SensorData> = serde_json::from_slice(&data);
-    match json_data {
-        Ok(mut sensor_data) => {
-            let hashes = keystore
-                .lock()
-                .expect("lock keystore")
-                .keystore
-                .api_keys_author
-                .clone();
-            if authenticate(&sensor_data.device, hashes.clone()) {
-                sensor_data.device.to_string().push_str("_id");
-                sensor_data.device = calculate_hash(sensor_data.device);
-                sensor_data.timestamp = serde_json::Value::from(timestamp_in_sec());
-                println!(
-                    "POST /sensor_data -- {:?} -- authorized request by device",
-                    timestamp_in_sec()
-                );
-                let mut channel_state = channel_state.lock().unwrap();
-                match channel_state.channel.write_signed(&sensor_data) {
-                    Ok(_) => {
-                        response = Response::builder()
-                            .status(StatusCode::OK)
-                            .header(header::CONTENT_TYPE, "application/json")
-                            .body(Body::from(channel_state.channel_id.clone()))?;
-                    }
-                    Err(_e) => {
-                        println!("POST /sensor_data Error: Connection to IOTA Node Error");
-                        response = Response::builder()
-                            .status(StatusCode::REQUEST_TIMEOUT)
-                            .header(header::CONTENT_TYPE, "application/json")
-                            .body(Body::from(
-                                "Could not connect to IOTA Node, try with another node!",
-                            ))?;
-                    }
+///
+/// Format of the body of a single reading
+///
+enum ReadingFormat {
+    Json,
+    Senml { cbor: bool },
+}
+
+impl ReadingFormat {
+    ///
+    /// decodes the body into the SensorData Format
+    ///
+    fn decode(&self, data: &[u8]) -> anyhow::Result<SensorData> {
+        match self {
+            ReadingFormat::Json => Ok(serde_json::from_slice(data)?),
+            ReadingFormat::Senml { cbor } => {
+                let pack = if *cbor {
+                    senml::pack_from_cbor(data)?
+                } else {
+                    serde_json::from_slice(data)?
                 };
-            } else {
-                response = Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .header(header::CONTENT_TYPE, "application/json")
-                    .body(Body::from(
-                        "Unauthorized - Device Name sent by device doesn't match the configuration",
-                    ))?;
-                println!(
-                    "POST /sensor_data -- {:?} -- unauthorized request blocked",
-                    timestamp_in_sec()
-                );
+                senml::pack_to_sensor_data(&pack)
             }
         }
-        Err(_e) => {
-            response = Response::builder()
+    }
+
+    fn malformed(&self, e: &anyhow::Error) -> String {
+        match self {
+            ReadingFormat::Json => "Malformed json - use iot2tangle json format".to_string(),
+            ReadingFormat::Senml { .. } => format!("Malformed SenML - {}", e),
+        }
+    }
+
+    fn unauthorized(&self) -> &'static str {
+        match self {
+            ReadingFormat::Json => {
+                "Unauthorized - Device Name sent by device doesn't match the configuration"
+            }
+            ReadingFormat::Senml { .. } => {
+                "Unauthorized - Base Name sent by device doesn't match the configuration"
+            }
+        }
+    }
+}
+
+///
+/// Reads and decodes a single reading, authenticates its device and publishes it,
+/// stores it for anchoring or adds it to the batch depending on the configuration
+///
+async fn reading_response(
+    req: Request<Body>,
+    format: ReadingFormat,
+    gateway: Arc<Gateway>,
+    keystore: Arc<Mutex<KeyManager>>,
+    config: Config,
+) -> Result<Response<Body>> {
+    let data = match read_body(req, config.limits.max_body_size, &config.limits).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!(error = %e, "request body rejected");
+            return body_error_response(&e);
+        }
+    };
+    metrics::BYTES_INGESTED.inc_by(data.len() as u64);
+
+    let mut sensor_data = match info_span!("parse").in_scope(|| format.decode(&data)) {
+        Ok(sensor_data) => sensor_data,
+        Err(e) => {
+            warn!(error = %e, "malformed request");
+            return Ok(Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .header(header::CONTENT_TYPE, "application/json")
-                .body(Body::from("Malformed json - use iot2tangle json format"))?;
+                .body(Body::from(format.malformed(&e)))?);
+        }
+    };
+
+    let hashes = keystore
+        .lock()
+        .expect("lock keystore")
+        .keystore
+        .api_keys_author
+        .clone();
+    if !authenticate(&sensor_data.device, hashes) {
+        warn!(device = %calculate_hash(sensor_data.device.clone()), "unauthorized request blocked");
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format.unauthorized()))?);
+    }
+    info!(device = %calculate_hash(sensor_data.device.clone()), "authorized request by device");
+    let device_id = sensor_data.device.clone();
+    sensor_data.device = calculate_hash(sensor_data.device);
+    metrics::device_seen(&sensor_data.device);
+    sensor_data.timestamp = serde_json::Value::from(timestamp_in_sec());
+    gateway.field_encryption.apply(&mut sensor_data);
+    let oversized = if config.publish_senml {
+        check_message_size(&senml::sensor_data_to_pack(&sensor_data), &config.limits)
+    } else {
+        check_message_size(&sensor_data, &config.limits)
+    };
+    if let Some(size) = oversized {
+        warn!(size, "message exceeds the Streams payload size");
+        return message_too_large_response(size, &config.limits);
+    }
+    if config.anchoring.is_some() {
+        return anchored_response(vec![sensor_data], gateway).await;
+    }
+    if config.batching.is_some() {
+        return batched_response(sensor_data, gateway, config);
+    }
+    match routing::publish(&gateway, &sensor_data, &device_id, &config).await {
+        Ok(channels) => routed_response(channels, &config),
+        Err(size) => {
+            warn!(size, "message exceeds the Streams payload size");
+            message_too_large_response(size, &config.limits)
         }
     }
-    Ok(response)
 }
 
+///
+/// Handles a bundle of data sent by several devices, all of them have to be whitelisted.
+/// Bundles too large for a single Streams message are split into parts, the response then lists the links of all messages
+///
 pub async fn send_bundle_response(
     req: Request<Body>,
-    channel_state: Arc<Mutex<ChannelState>>,
+    gateway: Arc<Gateway>,
     keystore: Arc<Mutex<KeyManager>>,
+    config: Config,
 ) -> Result<Response<Body>> {
-    let data = hyper::body::to_bytes(req.into_body()).await?;
+    let data = match read_body(req, config.limits.max_bundle_size, &config.limits).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!(error = %e, "request body rejected");
+            return body_error_response(&e);
+        }
+    };
+    metrics::BYTES_INGESTED.inc_by(data.len() as u64);
 
     let response;
 
-    let json_data: serde_json::Result<BundleData> = serde_json::from_slice(&data);
+    let json_data: serde_json::Result<BundleData> =
+        info_span!("parse").in_scope(|| serde_json::from_slice(&data));
     match json_data {
         Ok(mut bundle_data) => {
             let hashes = keystore
@@ -112,44 +467,71 @@ pub async fn send_bundle_response(
                 .api_keys_author
                 .clone();
 
-            println!(
-                "POST /bundle_data -- {:?} -- authorized request by device",
-                timestamp_in_sec()
-            );
+            let field_encryption = &gateway.field_encryption;
+            let device_ids: Vec<String> = bundle_data
+                .bundle
+                .iter()
+                .map(|sensor_data| sensor_data.device.clone())
+                .collect();
             let mut status: Vec<&str> = vec![];
             for mut sensor_data in &mut bundle_data.bundle {
                 if authenticate(&sensor_data.device, hashes.clone()) {
+                    info!(device = %calculate_hash(sensor_data.device.clone()), "authorized request by device");
                     sensor_data.device.to_string().push_str("_id");
                     sensor_data.device = calculate_hash(sensor_data.device.clone());
+                    metrics::device_seen(&sensor_data.device);
+                    field_encryption.apply(&mut sensor_data);
                     //sensor_data.timestamp = serde_json::Value::from(timestamp_in_sec());
                     status.push("OK");
                 } else {
                     status.push("UNAUTHORIZED");
-                    println!(
-                        "POST /bundle_data -- {:?} -- unauthorized request blocked",
-                        timestamp_in_sec()
-                    );
+                    warn!(device = %calculate_hash(sensor_data.device.clone()), "unauthorized request blocked");
                 }
             }
 
             if !status.contains(&"UNAUTHORIZED") {
-                let mut channel_state = channel_state.lock().unwrap();
-                match channel_state.channel.write_signed(&bundle_data) {
-                    Ok(_) => {
-                        response = Response::builder()
-                            .status(StatusCode::OK)
-                            .header(header::CONTENT_TYPE, "application/json")
-                            .body(Body::from(channel_state.channel_id.clone()))?;
+                if config.anchoring.is_some() {
+                    return anchored_response(bundle_data.bundle, gateway).await;
+                }
+                // every bundle is prepared before anything is published, so an item too large
+                // for a message does not leave the bundle published on some channels only
+                let mut prepared = vec![];
+                for (route, bundle) in
+                    routing::split_bundle(&config.routing, &bundle_data, &device_ids)
+                {
+                    match splitter::prepare(&bundle, &config) {
+                        Ok(messages) => prepared.push((route, bundle, messages)),
+                        Err(size) => {
+                            warn!(size, "bundle item exceeds the Streams payload size");
+                            return message_too_large_response(size, &config.limits);
+                        }
                     }
-                    Err(_e) => {
-                        response = Response::builder()
-                            .status(StatusCode::REQUEST_TIMEOUT)
-                            .header(header::CONTENT_TYPE, "application/json")
-                            .body(Body::from(
-                                "Could not connect to IOTA Node, try with another node!",
-                            ))?;
+                }
+                let mut channels = vec![];
+                for (route, bundle, messages) in prepared {
+                    let mut published =
+                        splitter::publish(&gateway, route.as_deref(), &messages).await;
+                    if let Err(partial) = &mut published {
+                        if partial.published.channel_id.is_empty() {
+                            partial.published.channel_id =
+                                publisher::channel_id(&gateway, route.as_deref())
+                                    .await
+                                    .unwrap_or_default();
+                        }
                     }
-                };
+                    let channel_id = match &published {
+                        Ok(published) => &published.channel_id,
+                        Err(partial) => &partial.published.channel_id,
+                    };
+                    for sensor_data in &bundle.bundle {
+                        notify(&gateway, channel_id, sensor_data, published.is_ok());
+                    }
+                    channels.push((
+                        route.unwrap_or_else(|| routing::DEFAULT_CHANNEL.to_string()),
+                        published,
+                    ));
+                }
+                response = bundle_response(channels, &config)?;
             } else {
                 response = Response::builder()
                     .status(StatusCode::UNAUTHORIZED)
@@ -159,7 +541,8 @@ pub async fn send_bundle_response(
                     ))?;
             }
         }
-        Err(_e) => {
+        Err(e) => {
+            warn!(error = %e, "malformed request");
             response = Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .header(header::CONTENT_TYPE, "application/json")
@@ -171,15 +554,22 @@ pub async fn send_bundle_response(
 
 pub async fn switch_channel_response(
     req: Request<Body>,
-    channel_state: Arc<Mutex<ChannelState>>,
+    gateway: Arc<Gateway>,
     keystore: Arc<Mutex<KeyManager>>,
     config: Config,
 ) -> Result<Response<Body>> {
-    let data = hyper::body::to_bytes(req.into_body()).await?;
+    let data = match read_body(req, config.limits.max_body_size, &config.limits).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!(error = %e, "request body rejected");
+            return body_error_response(&e);
+        }
+    };
 
     let response;
 
-    let json_data: serde_json::Result<SwitchAuth> = serde_json::from_slice(&data);
+    let json_data: serde_json::Result<SwitchAuth> =
+        info_span!("parse").in_scope(|| serde_json::from_slice(&data));
     match json_data {
         Ok(device_auth) => {
             let hashes = keystore
@@ -190,28 +580,28 @@ pub async fn switch_channel_response(
                 .clone();
 
             if authenticate(&device_auth.device, hashes.clone()) {
-                println!(
-                    "POST /switch_channel -- {:?} -- authorized request by device",
-                    timestamp_in_sec()
-                );
-
-                let mut channel = Channel::new(config.node, config.local_pow, None);
-                let (addr, msg_id) = match channel.open() {
-                    Ok(a) => a,
-                    Err(_) => {
+                info!(device = %calculate_hash(device_auth.device.clone()), "authorized request by device");
+
+                let replaced = rotation::replace_channel(
+                    &gateway,
+                    &keystore,
+                    &config,
+                    None,
+                    OpenReason::ManualSwitch,
+                )
+                .await;
+                let channel_id = match replaced {
+                    Ok(channel_id) => channel_id,
+                    Err(e) => {
+                        error!(error = %e, "could not open channel on IOTA node");
                         return Ok(Response::builder()
                             .status(StatusCode::REQUEST_TIMEOUT)
                             .header(header::CONTENT_TYPE, "application/json")
                             .body(Body::from(
                                 "Could not connect to IOTA Node, try with another node!",
-                            ))?)
+                            ))?);
                     }
                 };
-                let channel_id = format!("{}:{}", addr, msg_id);
-
-                let mut channel_state = channel_state.lock().expect("");
-                channel_state.channel = channel;
-                channel_state.channel_id = channel_id.clone();
 
                 response = Response::builder()
                     .status(StatusCode::OK)
@@ -224,13 +614,11 @@ pub async fn switch_channel_response(
                     .body(Body::from(
                         "Unauthorized - Device Name sent by device doesn't match the configuration",
                     ))?;
-                println!(
-                    "POST /switch_channel -- {:?} -- unauthorized request blocked",
-                    timestamp_in_sec()
-                );
+                warn!(device = %calculate_hash(device_auth.device.clone()), "unauthorized request blocked");
             }
         }
-        Err(_e) => {
+        Err(e) => {
+            warn!(error = %e, "malformed request");
             response = Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .header(header::CONTENT_TYPE, "application/json")
@@ -242,17 +630,26 @@ pub async fn switch_channel_response(
 
 pub async fn get_current_channel(
     req: Request<Body>,
-    channel_state: Arc<Mutex<ChannelState>>,
+    gateway: Arc<Gateway>,
     keystore: Arc<Mutex<KeyManager>>,
+    config: Config,
 ) -> Result<Response<Body>> {
     let req_uri = &req.uri().to_string().parse::<Uri>().unwrap();
-    let device_from_query = req_uri.query();
+    let device_from_query = device_from_query(req_uri);
+    let channel = query_param(req_uri, "channel").filter(|channel| !channel.is_empty());
 
-    let data = hyper::body::to_bytes(req.into_body()).await?;
+    let data = match read_body(req, config.limits.max_body_size, &config.limits).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!(error = %e, "request body rejected");
+            return body_error_response(&e);
+        }
+    };
 
     let response;
 
-    let json_data: serde_json::Result<SwitchAuth> = serde_json::from_slice(&data);
+    let json_data: serde_json::Result<SwitchAuth> =
+        info_span!("parse").in_scope(|| serde_json::from_slice(&data));
 
     let hashes = keystore
         .lock()
@@ -264,18 +661,9 @@ pub async fn get_current_channel(
     match json_data {
         Ok(device_auth) => {
             if authenticate(&device_auth.device, hashes.clone()) {
-                println!(
-                    "GET /current_channel -- {:?} -- authorized request by device",
-                    timestamp_in_sec()
-                );
-
-                let channel_state = channel_state.lock().expect("");
-                let channel_id = channel_state.channel_id.clone();
+                info!(device = %calculate_hash(device_auth.device.clone()), "authorized request by device");
 
-                response = Response::builder()
-                    .status(StatusCode::OK)
-                    .header(header::CONTENT_TYPE, "application/json")
-                    .body(Body::from(channel_id))?;
+                response = current_channel_response(&gateway, channel.as_deref())?;
             } else {
                 response = Response::builder()
                     .status(StatusCode::UNAUTHORIZED)
@@ -283,28 +671,16 @@ pub async fn get_current_channel(
                     .body(Body::from(
                         "Unauthorized - Device Name sent by device doesn't match the configuration",
                     ))?;
-                println!(
-                    "GET /current_channel -- {:?} -- unauthorized request blocked",
-                    timestamp_in_sec()
-                );
+                warn!(device = %calculate_hash(device_auth.device.clone()), "unauthorized request blocked");
             }
         }
         // if there is no json in the body => check Uri
         Err(_) => match device_from_query {
             Some(id) => {
                 if authenticate(&id, hashes.clone()) {
-                    println!(
-                        "GET /current_channel -- {:?} -- authorized request by device",
-                        timestamp_in_sec()
-                    );
+                    info!(device = %calculate_hash(id.clone()), "authorized request by device");
 
-                    let channel_state = channel_state.lock().expect("");
-                    let channel_id = channel_state.channel_id.clone();
-
-                    response = Response::builder()
-                        .status(StatusCode::OK)
-                        .header(header::CONTENT_TYPE, "application/json")
-                        .body(Body::from(channel_id))?;
+                    response = current_channel_response(&gateway, channel.as_deref())?;
                 } else {
                     response = Response::builder()
                     .status(StatusCode::UNAUTHORIZED)
@@ -312,10 +688,7 @@ pub async fn get_current_channel(
                     .body(Body::from(
                         "Unauthorized - Device Name sent by device doesn't match the configuration",
                     ))?;
-                    println!(
-                        "GET /current_channel -- {:?} -- unauthorized request blocked",
-                        timestamp_in_sec()
-                    );
+                    warn!(device = %calculate_hash(id.clone()), "unauthorized request blocked");
                 }
             }
             None => {
@@ -325,12 +698,115 @@ pub async fn get_current_channel(
                     .body(Body::from(
                         "Unauthorized - No device_id provided in Request Body or Uri",
                     ))?;
-                println!(
-                    "GET /current_channel -- {:?} -- unauthorized request blocked",
-                    timestamp_in_sec()
-                );
+                warn!("unauthorized request blocked");
             }
         },
     }
     Ok(response)
 }
+
+///
+/// the device of the "device" query parameter, or of the legacy form where the query is the device id (?DEVICE_ID_1)
+///
+fn device_from_query(uri: &Uri) -> Option<String> {
+    if let Some(device) = query_param(uri, "device") {
+        return Some(device);
+    }
+    form_urlencoded::parse(uri.query()?.as_bytes())
+        .find(|(key, value)| !key.is_empty() && value.is_empty() && key != "channel")
+        .map(|(key, _)| key.to_string())
+}
+
+///
+/// responds with the announcement of the named channel, the default channel if no name is given,
+/// together with the node it was opened on, the time it was opened and the number of messages published on it
+///
+fn current_channel_response(
+    gateway: &Arc<Gateway>,
+    channel: Option<&str>,
+) -> Result<Response<Body>> {
+    let route = channel.filter(|channel| *channel != routing::DEFAULT_CHANNEL);
+    let target = gateway
+        .channel_state
+        .lock()
+        .expect("")
+        .target(route)
+        .map(|target| target.channel_id.clone());
+    let (name, channel_id) = match target {
+        Ok(channel_id) => (route.unwrap_or(routing::DEFAULT_CHANNEL), channel_id),
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(e.to_string()))?)
+        }
+    };
+    // the channel id is the announcement link "{address}:{message id}"
+    let mut link = channel_id.rsplitn(2, ':');
+    let (message_id, address) = match (link.next(), link.next()) {
+        (Some(message_id), Some(address)) if !message_id.is_empty() && !address.is_empty() => {
+            (message_id, address)
+        }
+        _ => {
+            error!(channel_id = %channel_id, "channel id is not an announcement link");
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(
+                    "Channel id {} is not an announcement link",
+                    channel_id
+                )))?);
+        }
+    };
+    let history = gateway.history.lock().unwrap();
+    let record = history.record(&channel_id);
+    let node = gateway.nodes.lock().unwrap().active.clone();
+
+    let body = json!({
+        "channel": name,
+        "channel_id": channel_id,
+        "announcement_address": address,
+        "message_id": message_id,
+        "announcement_link": format!("{}/api/v1/messages/{}", node.trim_end_matches('/'), message_id),
+        "node": node,
+        "opened_at": record.map(|record| record.opened_at),
+        "messages": record.map_or(0, |record| record.messages),
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))?)
+}
+
+///
+/// Handles the request for the history of the channels opened by the gateway,
+/// authenticated through the "reader" or "device" query parameter
+///
+pub async fn channels_response(
+    req: Request<Body>,
+    gateway: Arc<Gateway>,
+    keystore: Arc<Mutex<KeyManager>>,
+) -> Result<Response<Body>> {
+    let authorized = {
+        let keystore = keystore.lock().expect("lock keystore");
+        let reader = query_param(req.uri(), "reader")
+            .map(|reader| authenticate(&reader, keystore.keystore.api_keys_reader.clone()));
+        let device = query_param(req.uri(), "device")
+            .map(|device| authenticate(&device, keystore.keystore.api_keys_author.clone()));
+        reader == Some(true) || device == Some(true)
+    };
+    if !authorized {
+        warn!("unauthorized request blocked");
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                "Unauthorized - No whitelisted reader or device provided in Uri",
+            ))?);
+    }
+    let channels = gateway.history.lock().unwrap().records();
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&channels)?))?)
+}
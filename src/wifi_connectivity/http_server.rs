@@ -1,65 +1,190 @@
 use crate::device_auth::keystore::KeyManager;
-use crate::types::{channel_state::ChannelState, config::Config};
+use crate::monitoring::{health, logging, metrics};
+use crate::shutdown;
+use crate::types::{config::Config, gateway::Gateway};
+use crate::wifi_connectivity::event_stream::stream_response;
 use crate::wifi_connectivity::handlers::*;
+use crate::wifi_connectivity::limits::HeaderTimeoutStream;
+use crate::wifi_connectivity::websocket::websocket_response;
 
 use hyper::service::{make_service_fn, service_fn};
 
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use futures::stream;
+use hyper::header::HeaderValue;
+use hyper::server::accept;
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use tokio::net::TcpListener;
+use tracing::{field, info, info_span, warn, Instrument};
 type GenericError = Box<dyn std::error::Error + Send + Sync>;
 type Result<T> = std::result::Result<T, GenericError>;
 static NOTFOUND: &[u8] = b"Not Found";
+static REQUEST_ID: &str = "x-request-id";
 
 ///
-/// Starts the server on the provided port, the server will hand over requests to the handler functions
+/// Starts the server on the configured address and port, the server will hand over requests to the handler functions.
+/// Once the shutdown is requested no new connections are accepted and the open requests are finished
+/// until "shutdown_timeout" is reached
 ///
 pub async fn start(
     config: Config,
-    channel_state: Arc<Mutex<ChannelState>>,
+    gateway: Arc<Gateway>,
     keystore: Arc<Mutex<KeyManager>>,
 ) -> Result<()> {
-    let addr = ([0, 0, 0, 0], config.port).into();
+    let ip: IpAddr = config.bind_address.parse()?;
+    let addr = SocketAddr::new(ip, config.port);
+    let shutdown_timeout = config.shutdown_timeout;
 
-    let service = make_service_fn(move |_| {
-        let channel_state = channel_state.clone();
+    let header_timeout = Duration::from_secs(config.limits.header_timeout);
+
+    let service = make_service_fn(move |conn: &HeaderTimeoutStream| {
+        let state = conn.state.clone();
+        let gateway = gateway.clone();
         let keystore = keystore.clone();
         let config = config.clone();
         async {
             Ok::<_, GenericError>(service_fn(move |req| {
-                responder(req, channel_state.clone(), keystore.clone(), config.clone())
+                state.request_started();
+                let state = state.clone();
+                let response =
+                    traced_responder(req, gateway.clone(), keystore.clone(), config.clone());
+                async move { response.await.map(|response| state.track(response)) }
             }))
         }
     });
 
-    let server = Server::bind(&addr).serve(service);
+    let listener = TcpListener::bind(&addr).await?;
+    let incoming = stream::unfold(listener, move |mut listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let stream = HeaderTimeoutStream::new(stream, header_timeout);
+                    return Some((Ok::<_, GenericError>(stream), listener));
+                }
+                // e.g. too many open files, the next connection may succeed
+                Err(e) => {
+                    warn!(error = %e, "could not accept connection");
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    });
+
+    let server = Server::builder(accept::from_stream(incoming))
+        .serve(service)
+        .with_graceful_shutdown(shutdown::requested());
 
-    println!("Listening on http://{}", addr);
+    info!("Listening on http://{}", addr);
 
-    server.await?;
+    let timeout = Duration::from_secs(shutdown_timeout);
+    tokio::select! {
+        result = server => result?,
+        _ = async {
+            shutdown::requested().await;
+            tokio::time::delay_for(shutdown::remaining(timeout)).await;
+        } => warn!("shutdown deadline reached, open requests are dropped"),
+    }
 
     Ok(())
 }
 
+///
+/// Runs the responder inside a span carrying a request id generated by the gateway and echoed in the response.
+/// An X-Request-Id sent by the client is only logged as "client_request_id"
+///
+async fn traced_responder(
+    req: Request<Body>,
+    gateway: Arc<Gateway>,
+    keystore: Arc<Mutex<KeyManager>>,
+    config: Config,
+) -> Result<Response<Body>> {
+    let request_id = logging::request_id();
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        client_request_id = field::Empty,
+        protocol = "http",
+        method = %req.method(),
+        route = %req.uri().path()
+    );
+    let client_request_id = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 64);
+    if let Some(client_request_id) = client_request_id {
+        span.record("client_request_id", client_request_id);
+    }
+
+    let in_flight = metrics::InFlightGuard::enter();
+    let response = responder(req, gateway, keystore, config)
+        .instrument(span.clone())
+        .await;
+    drop(in_flight);
+    let mut response = response?;
+    span.in_scope(|| info!(status = %response.status(), "request handled"));
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    Ok(response)
+}
+
 async fn responder(
     req: Request<Body>,
-    channel_state: Arc<Mutex<ChannelState>>,
+    gateway: Arc<Gateway>,
     keystore: Arc<Mutex<KeyManager>>,
     config: Config,
 ) -> Result<Response<Body>> {
-    match (req.method(), req.uri().path()) {
-        (&Method::POST, "/sensor_data") => sensor_data_response(req, channel_state, keystore).await,
-        (&Method::POST, "/bundle_data") => send_bundle_response(req, channel_state, keystore).await,
+    let route = route_label(req.uri().path());
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::POST, "/sensor_data") => {
+            sensor_data_response(req, gateway, keystore, config).await
+        }
+        (&Method::POST, "/bundle_data") => {
+            send_bundle_response(req, gateway, keystore, config).await
+        }
+        (&Method::POST, "/senml") => senml_response(req, gateway, keystore, config).await,
         (&Method::POST, "/switch_channel") => {
-            switch_channel_response(req, channel_state, keystore, config).await
+            switch_channel_response(req, gateway, keystore, config).await
         }
         (&Method::GET, "/current_channel") => {
-            get_current_channel(req, channel_state, keystore).await
+            get_current_channel(req, gateway, keystore, config).await
         }
-        (&Method::GET, "/status") => status_response().await,
-        _ => Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(NOTFOUND.into())
-            .unwrap()),
+        (&Method::GET, "/ws") => websocket_response(req, gateway, keystore, config).await,
+        (&Method::GET, "/stream") => stream_response(req, gateway, keystore).await,
+        (&Method::GET, "/channels") => channels_response(req, gateway, keystore).await,
+        (&Method::GET, "/status") => status_response(gateway).await,
+        (&Method::GET, path) if path.starts_with("/receipts/") => {
+            receipt_response(req, gateway).await
+        }
+        (&Method::GET, path) if path.starts_with("/proof/") => proof_response(req, gateway).await,
+        (&Method::GET, "/metrics") => metrics::metrics_response().await,
+        (&Method::GET, "/health/live") => health::live_response().await,
+        (&Method::GET, "/health/ready") => health::ready_response(gateway, keystore).await,
+        _ => {
+            metrics::record_request("http", "other", StatusCode::NOT_FOUND);
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(NOTFOUND.into())
+                .unwrap());
+        }
+    }?;
+    metrics::record_request("http", &route, response.status());
+    Ok(response)
+}
+
+///
+/// the route recorded in the metrics, ids in the path are replaced to keep the number of series bounded
+///
+fn route_label(path: &str) -> String {
+    if path.starts_with("/receipts/") {
+        "/receipts/{id}".to_string()
+    } else if path.starts_with("/proof/") {
+        "/proof/{id}".to_string()
+    } else {
+        path.to_string()
     }
 }
@@ -0,0 +1,335 @@
+use crate::types::config::LimitsConfig;
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::task::AtomicWaker;
+use http_body::SizeHint;
+use hyper::body::{Bytes, HttpBody};
+use hyper::{header, Body, HeaderMap, Request, Response, StatusCode};
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::Delay;
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge(usize),
+    Timeout,
+    Read(hyper::Error),
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BodyError::TooLarge(limit) => write!(f, "body larger than {} bytes", limit),
+            BodyError::Timeout => write!(f, "body not received in time"),
+            BodyError::Read(e) => write!(f, "could not read body: {}", e),
+        }
+    }
+}
+
+///
+/// Reads the body of the request, failing as soon as it exceeds the limit or takes longer than "read_timeout"
+///
+pub async fn read_body(
+    req: Request<Body>,
+    limit: usize,
+    limits: &LimitsConfig,
+) -> std::result::Result<Bytes, BodyError> {
+    let announced = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if announced.map(|len| len > limit).unwrap_or(false) {
+        return Err(BodyError::TooLarge(limit));
+    }
+
+    let mut body = req.into_body();
+    let read = async {
+        let mut data = Vec::with_capacity(announced.unwrap_or(0));
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(BodyError::Read)?;
+            if data.len() + chunk.len() > limit {
+                return Err(BodyError::TooLarge(limit));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(Bytes::from(data))
+    };
+    match tokio::time::timeout(Duration::from_secs(limits.read_timeout), read).await {
+        Ok(result) => result,
+        Err(_) => Err(BodyError::Timeout),
+    }
+}
+
+///
+/// Builds the response for a body that could not be read, 413 if it is too large and 408 if it took too long
+///
+pub fn body_error_response(e: &BodyError) -> Result<Response<Body>> {
+    let (status, message) = match e {
+        BodyError::TooLarge(limit) => (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Payload too large - the limit is {} bytes", limit),
+        ),
+        BodyError::Timeout => (
+            StatusCode::REQUEST_TIMEOUT,
+            "Request timeout - the body was not received in time".to_string(),
+        ),
+        BodyError::Read(_) => (
+            StatusCode::BAD_REQUEST,
+            "Could not read the request body".to_string(),
+        ),
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(message))?)
+}
+
+///
+/// Checks that the payload fits into a single Streams message once serialized
+///
+pub fn check_message_size<T: Serialize>(payload: &T, limits: &LimitsConfig) -> Option<usize> {
+    let size = serde_json::to_vec(payload).map(|p| p.len()).unwrap_or(0);
+    if size > limits.max_message_size {
+        Some(size)
+    } else {
+        None
+    }
+}
+
+///
+/// Builds the 413 response for a payload that does not fit into a Streams message
+///
+pub fn message_too_large_response(size: usize, limits: &LimitsConfig) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(format!(
+            "Payload too large - the message has {} bytes, a Streams message can hold {} bytes",
+            size, limits.max_message_size
+        )))?)
+}
+
+///
+/// Requests of a connection, shared between the connection and its service. A request counts as finished
+/// once the body of its response was sent, an upgraded connection (WebSocket) is no longer HTTP
+///
+#[derive(Default)]
+pub struct ConnectionState {
+    started: AtomicUsize,
+    finished: AtomicUsize,
+    upgraded: AtomicBool,
+    idle: AtomicWaker,
+}
+
+impl ConnectionState {
+    ///
+    /// Lifts the deadline of the connection until the response to this request was sent
+    ///
+    pub fn request_started(&self) {
+        self.started.fetch_add(1, Ordering::SeqCst);
+    }
+
+    ///
+    /// Wraps the body of the response, the deadline is armed again for the next request once it was sent
+    ///
+    pub fn track(self: &Arc<Self>, response: Response<Body>) -> Response<TrackedBody> {
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            self.upgraded.store(true, Ordering::SeqCst);
+        }
+        response.map(|body| TrackedBody {
+            inner: body,
+            state: self.clone(),
+        })
+    }
+}
+
+///
+/// Response body which marks its request as finished when hyper drops it after sending it
+///
+pub struct TrackedBody {
+    inner: Body,
+    state: Arc<ConnectionState>,
+}
+
+impl HttpBody for TrackedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Bytes, hyper::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::result::Result<Option<HeaderMap>, hyper::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for TrackedBody {
+    fn drop(&mut self) {
+        self.state.finished.fetch_add(1, Ordering::SeqCst);
+        // the connection may already wait for the next request without a deadline
+        self.state.idle.wake();
+    }
+}
+
+///
+/// TCP connection which is closed if the headers of a request are not received within the timeout, counted from
+/// the accept and on keep-alive connections from the end of the previous response. The deadline is lifted while
+/// a request is served, so long-lived responses and upgraded connections are not affected
+///
+pub struct HeaderTimeoutStream {
+    inner: TcpStream,
+    timeout: Duration,
+    deadline: Option<Delay>,
+    armed_after: usize,
+    pub state: Arc<ConnectionState>,
+}
+
+impl HeaderTimeoutStream {
+    pub fn new(inner: TcpStream, timeout: Duration) -> HeaderTimeoutStream {
+        HeaderTimeoutStream {
+            inner: inner,
+            timeout: timeout,
+            deadline: Some(tokio::time::delay_for(timeout)),
+            armed_after: 0,
+            state: Arc::new(ConnectionState::default()),
+        }
+    }
+}
+
+impl AsyncRead for HeaderTimeoutStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.state.idle.register(cx.waker());
+        let finished = self.state.finished.load(Ordering::SeqCst);
+        if self.state.upgraded.load(Ordering::SeqCst)
+            || self.state.started.load(Ordering::SeqCst) > finished
+        {
+            self.deadline = None;
+        } else if self.deadline.is_none() || self.armed_after != finished {
+            // idle again, the headers of the next request have to arrive within the timeout
+            self.deadline = Some(tokio::time::delay_for(self.timeout));
+            self.armed_after = finished;
+        }
+        if let Some(deadline) = self.deadline.as_mut() {
+            if Pin::new(deadline).poll(cx).is_ready() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "request headers not received in time",
+                )));
+            }
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for HeaderTimeoutStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    ///
+    /// serves one connection with the header timeout, answering every request with "ok" after the delay
+    ///
+    async fn connect(timeout: Duration, delay: Duration) -> TcpStream {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = HeaderTimeoutStream::new(stream, timeout);
+            let state = stream.state.clone();
+            let service = service_fn(move |_req| {
+                state.request_started();
+                let state = state.clone();
+                async move {
+                    tokio::time::delay_for(delay).await;
+                    Ok::<_, hyper::Error>(state.track(Response::new(Body::from("ok"))))
+                }
+            });
+            let _ = Http::new().serve_connection(stream, service).await;
+        });
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    async fn response(client: &mut TcpStream) -> String {
+        let mut buf = [0; 256];
+        let n = client.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    #[tokio::test]
+    async fn closes_idle_keep_alive_connection() {
+        let mut client = connect(Duration::from_millis(300), Duration::from_millis(0)).await;
+        client.write_all(REQUEST).await.unwrap();
+        assert!(response(&mut client).await.starts_with("HTTP/1.1 200"));
+
+        // the next request within the timeout is still served
+        tokio::time::delay_for(Duration::from_millis(150)).await;
+        client.write_all(REQUEST).await.unwrap();
+        assert!(response(&mut client).await.starts_with("HTTP/1.1 200"));
+
+        let mut buf = [0; 16];
+        let closed = tokio::time::timeout(Duration::from_secs(2), client.read(&mut buf)).await;
+        assert_eq!(closed.unwrap().unwrap_or(0), 0);
+    }
+
+    #[tokio::test]
+    async fn slow_response_is_not_cut() {
+        let mut client = connect(Duration::from_millis(200), Duration::from_millis(500)).await;
+        client.write_all(REQUEST).await.unwrap();
+        assert!(response(&mut client).await.ends_with("ok"));
+    }
+}
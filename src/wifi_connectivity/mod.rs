@@ -5,3 +5,23 @@ pub mod http_server;
 ///
 /// handling of requests sent to server
 pub mod handlers;
+
+///
+/// WebSocket connections for devices streaming data
+pub mod websocket;
+
+///
+/// live feed of the data received, sent as Server-Sent Events
+pub mod event_stream;
+
+///
+/// client subscribing to an MQTT broker and forwarding messages to the handlers
+pub mod mqtt_client;
+
+///
+/// CoAP server providing the endpoints for constrained devices
+pub mod coap_server;
+
+///
+/// limits for the size and the read time of requests
+pub mod limits;
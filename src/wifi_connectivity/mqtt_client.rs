@@ -0,0 +1,697 @@
+use crate::device_auth::keystore::KeyManager;
+use crate::monitoring::{logging, metrics};
+use crate::shutdown;
+use crate::types::{
+    config::{Config, LimitsConfig, MqttConfig},
+    gateway::Gateway,
+};
+use crate::wifi_connectivity::handlers::*;
+
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Request, StatusCode};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{info, info_span, warn, Instrument};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PUBREC: u8 = 0x50;
+const PUBREL: u8 = 0x60;
+const PUBCOMP: u8 = 0x70;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+/// largest packet other than PUBLISH read from the broker
+const MAX_CONTROL_PACKET: usize = 4096;
+/// messages waiting to be handled before the connection is no longer read
+const MAX_QUEUED: usize = 32;
+
+///
+/// Connects to the configured MQTT broker and subscribes to the topics, messages received are handed over
+/// to the same handler functions used by the http server. Topics ending in "bundle_data" are treated as bundles,
+/// every other topic as sensor data. The client reconnects if the connection to the broker is lost
+/// and disconnects once the shutdown is requested
+///
+pub async fn start(config: Config, gateway: Arc<Gateway>, keystore: Arc<Mutex<KeyManager>>) {
+    let mqtt = match config.mqtt.clone() {
+        Some(mqtt) => mqtt,
+        None => return,
+    };
+    loop {
+        tokio::select! {
+            result = run(&mqtt, &config, &gateway, &keystore) => {
+                if let Err(e) = result {
+                    warn!(error = %e, "connection to MQTT broker lost");
+                }
+            }
+            _ = shutdown::requested() => break,
+        }
+        tokio::select! {
+            _ = tokio::time::delay_for(Duration::from_secs(mqtt.reconnect_interval)) => {}
+            _ = shutdown::requested() => break,
+        }
+    }
+    info!("MQTT client stopped");
+}
+
+async fn run(
+    mqtt: &MqttConfig,
+    config: &Config,
+    gateway: &Arc<Gateway>,
+    keystore: &Arc<Mutex<KeyManager>>,
+) -> Result<()> {
+    let stream = TcpStream::connect(format!("{}:{}", mqtt.host, mqtt.port)).await?;
+    session(stream, mqtt, &config.limits, |route, topic, payload| {
+        handle(route, topic, payload, config, gateway, keystore)
+    })
+    .await
+}
+
+///
+/// Connects and subscribes on the stream, then hands the received messages to "handle" until the connection
+/// is lost. Messages larger than the limit of their route are skipped without being read into memory.
+/// Messages are handled one after the other in the order they arrived while the connection keeps being read
+/// and pinged, up to MAX_QUEUED messages wait for their turn before the broker is no longer read.
+/// QoS 1 messages are acknowledged with PUBACK once handled, QoS 2 messages with PUBREC and PUBCOMP and handled
+/// only once. A message that could not be handled is logged and acknowledged like the others
+///
+async fn session<F, Fut>(
+    mut stream: TcpStream,
+    mqtt: &MqttConfig,
+    limits: &LimitsConfig,
+    mut handle: F,
+) -> Result<()>
+where
+    F: FnMut(&'static str, String, Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let keep_alive = Duration::from_secs(u64::from(mqtt.keep_alive.max(1)));
+
+    stream.write_all(&connect_packet(mqtt)).await?;
+    let (header, body) = read_packet(&mut stream).await?;
+    if header != CONNACK || body.len() < 2 || body[1] != 0 {
+        return Err("Broker refused the connection".into());
+    }
+
+    stream.write_all(&subscribe_packet(&mqtt.topics)).await?;
+    let (header, _) = read_packet(&mut stream).await?;
+    if header != SUBACK {
+        return Err("Broker did not acknowledge the subscription".into());
+    }
+
+    info!(
+        topics = ?mqtt.topics,
+        "subscribed on mqtt://{}:{}",
+        mqtt.host,
+        mqtt.port
+    );
+
+    // ids of the QoS 2 messages handled but not yet released by the broker
+    let mut unreleased: HashSet<u16> = HashSet::new();
+    let mut queue: VecDeque<Received> = VecDeque::new();
+    let mut handling: Option<(Pin<Box<Fut>>, Received)> = None;
+    let mut last_sent = Instant::now();
+    loop {
+        while handling.is_none() {
+            let received = match queue.pop_front() {
+                Some(received) => received,
+                None => break,
+            };
+            match received.payload.clone() {
+                Some(payload) => {
+                    let handled = handle(received.route, received.topic.clone(), payload);
+                    handling = Some((Box::pin(handled), received));
+                }
+                None => {
+                    if acknowledge(&mut stream, &received, &mut unreleased).await? {
+                        last_sent = Instant::now();
+                    }
+                }
+            }
+        }
+
+        let wait = keep_alive
+            .checked_sub(last_sent.elapsed())
+            .unwrap_or_else(|| Duration::from_secs(0));
+        tokio::select! {
+            first = stream.read_u8(), if queue.len() < MAX_QUEUED => {
+                let first = first?;
+                if let Some(received) = read_message(&mut stream, first, limits, &mut unreleased).await? {
+                    queue.push_back(received);
+                } else if first & 0xF0 == PUBREL {
+                    last_sent = Instant::now();
+                }
+            }
+            result = async { handling.as_mut().unwrap().0.as_mut().await }, if handling.is_some() => {
+                let (_, received) = handling.take().unwrap();
+                if let Err(e) = result {
+                    warn!(error = %e, topic = %received.topic, "MQTT message could not be handled");
+                }
+                if acknowledge(&mut stream, &received, &mut unreleased).await? {
+                    last_sent = Instant::now();
+                }
+            }
+            _ = tokio::time::delay_for(wait) => {
+                stream.write_all(&[PINGREQ, 0]).await?;
+                last_sent = Instant::now();
+            }
+        }
+    }
+}
+
+///
+/// PUBLISH received from the broker, waiting to be handled and acknowledged
+///
+struct Received {
+    route: &'static str,
+    topic: String,
+    /// None for a message dropped without handling it, which is only acknowledged
+    payload: Option<Vec<u8>>,
+    packet_id: Option<u16>,
+    qos: u8,
+}
+
+///
+/// Reads the rest of the packet whose first byte has already been read. A PUBLISH is returned to be handled,
+/// a PUBREL is answered with PUBCOMP and other packets are skipped
+///
+async fn read_message(
+    stream: &mut TcpStream,
+    first: u8,
+    limits: &LimitsConfig,
+    unreleased: &mut HashSet<u16>,
+) -> Result<Option<Received>> {
+    let len = read_length(stream).await?;
+
+    match first & 0xF0 {
+        PUBLISH => {}
+        PUBREL => {
+            let body = read_limited(stream, len, MAX_CONTROL_PACKET).await?;
+            if body.len() < 2 {
+                return Err("Malformed PUBREL packet".into());
+            }
+            unreleased.remove(&u16::from_be_bytes([body[0], body[1]]));
+            stream.write_all(&[PUBCOMP, 2, body[0], body[1]]).await?;
+            return Ok(None);
+        }
+        _ => {
+            read_limited(stream, len, MAX_CONTROL_PACKET).await?;
+            return Ok(None);
+        }
+    }
+
+    let qos = (first >> 1) & 0x03;
+    if qos == 3 || len < 2 {
+        return Err("Malformed PUBLISH packet".into());
+    }
+    let topic_len = stream.read_u16().await? as usize;
+    let mut remaining = len - 2;
+    let topic = read_limited(stream, topic_len, remaining).await?;
+    let topic = String::from_utf8_lossy(&topic).to_string();
+    remaining -= topic_len;
+    let mut packet_id = None;
+    if qos > 0 {
+        if remaining < 2 {
+            return Err("Malformed PUBLISH packet".into());
+        }
+        packet_id = Some(stream.read_u16().await?);
+        remaining -= 2;
+    }
+
+    let route = if topic.ends_with("bundle_data") {
+        "/bundle_data"
+    } else {
+        "/sensor_data"
+    };
+    let limit = if route == "/bundle_data" {
+        limits.max_bundle_size
+    } else {
+        limits.max_body_size
+    };
+    let payload = if remaining > limit {
+        discard(stream, remaining).await?;
+        warn!(topic = %topic, size = remaining, limit, "MQTT message too large, dropped");
+        metrics::record_request("mqtt", route, StatusCode::PAYLOAD_TOO_LARGE);
+        None
+    } else {
+        let payload = read_limited(stream, remaining, limit).await?;
+        match packet_id {
+            Some(id) if qos == 2 && unreleased.contains(&id) => None,
+            _ => Some(payload),
+        }
+    };
+    Ok(Some(Received {
+        route: route,
+        topic: topic,
+        payload: payload,
+        packet_id: packet_id,
+        qos: qos,
+    }))
+}
+
+///
+/// Acknowledges a QoS 1 message with PUBACK and a QoS 2 message with PUBREC, returns if a packet was sent
+///
+async fn acknowledge(
+    stream: &mut TcpStream,
+    received: &Received,
+    unreleased: &mut HashSet<u16>,
+) -> Result<bool> {
+    match received.packet_id {
+        Some(id) if received.qos == 2 => {
+            unreleased.insert(id);
+            let id = id.to_be_bytes();
+            stream.write_all(&[PUBREC, 2, id[0], id[1]]).await?;
+        }
+        Some(id) => {
+            let id = id.to_be_bytes();
+            stream.write_all(&[PUBACK, 2, id[0], id[1]]).await?;
+        }
+        None => return Ok(false),
+    }
+    Ok(true)
+}
+
+///
+/// Hands a message over to the handler of its route
+///
+async fn handle(
+    route: &'static str,
+    topic: String,
+    payload: Vec<u8>,
+    config: &Config,
+    gateway: &Arc<Gateway>,
+    keystore: &Arc<Mutex<KeyManager>>,
+) -> Result<()> {
+    let req = Request::new(Body::from(payload));
+    let span = info_span!(
+        "request",
+        request_id = %logging::request_id(),
+        protocol = "mqtt",
+        route,
+        topic = %topic
+    );
+    let response = if route == "/bundle_data" {
+        send_bundle_response(req, gateway.clone(), keystore.clone(), config.clone())
+            .instrument(span.clone())
+            .await?
+    } else {
+        sensor_data_response(req, gateway.clone(), keystore.clone(), config.clone())
+            .instrument(span.clone())
+            .await?
+    };
+    metrics::record_request("mqtt", route, response.status());
+    span.in_scope(|| info!(status = %response.status(), "request handled"));
+    Ok(())
+}
+
+///
+/// Reads the remaining length of a packet whose first byte has already been read
+///
+async fn read_length(stream: &mut TcpStream) -> Result<usize> {
+    let mut len: usize = 0;
+    let mut shift = 0;
+    loop {
+        let byte = stream.read_u8().await?;
+        len += ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 21 {
+            return Err("Malformed remaining length".into());
+        }
+    }
+    Ok(len)
+}
+
+///
+/// Reads len bytes, failing before anything is read if len exceeds max
+///
+async fn read_limited(stream: &mut TcpStream, len: usize, max: usize) -> Result<Vec<u8>> {
+    if len > max {
+        return Err(format!(
+            "MQTT packet of {} bytes exceeds the limit of {} bytes",
+            len, max
+        )
+        .into());
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+///
+/// Skips len bytes of the stream
+///
+async fn discard(stream: &mut TcpStream, len: usize) -> Result<()> {
+    let skipped = tokio::io::copy(&mut stream.take(len as u64), &mut tokio::io::sink()).await?;
+    if skipped < len as u64 {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+async fn read_packet(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    let first = stream.read_u8().await?;
+    let len = read_length(stream).await?;
+    let body = read_limited(stream, len, MAX_CONTROL_PACKET).await?;
+    Ok((first & 0xF0, body))
+}
+
+fn packet(header: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+fn push_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend(&(s.len() as u16).to_be_bytes());
+    buf.extend(s.as_bytes());
+}
+
+fn connect_packet(mqtt: &MqttConfig) -> Vec<u8> {
+    let mut flags = 0x02; // clean session
+    if mqtt.username.is_some() {
+        flags |= 0x80;
+    }
+    // MQTT 3.1.1 allows a password only together with a user name
+    if mqtt.username.is_some() && mqtt.password.is_some() {
+        flags |= 0x40;
+    }
+    let mut body = vec![];
+    push_str(&mut body, "MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend(&mqtt.keep_alive.to_be_bytes());
+    push_str(&mut body, &mqtt.client_id);
+    if let Some(username) = &mqtt.username {
+        push_str(&mut body, username);
+    }
+    if let (Some(_), Some(password)) = (&mqtt.username, &mqtt.password) {
+        push_str(&mut body, password);
+    }
+    packet(CONNECT, body)
+}
+
+fn subscribe_packet(topics: &[String]) -> Vec<u8> {
+    let mut body = vec![0, 1];
+    for topic in topics {
+        push_str(&mut body, topic);
+        body.push(1); // QoS 1
+    }
+    packet(SUBSCRIBE, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    type Handled = Arc<Mutex<Vec<(&'static str, String, Vec<u8>)>>>;
+
+    fn mqtt_config(port: u16, credentials: serde_json::Value) -> MqttConfig {
+        let mut mqtt = serde_json::json!({
+            "host": "127.0.0.1",
+            "port": port,
+            "topics": ["iot2tangle/+/sensor_data", "iot2tangle/+/bundle_data"],
+        });
+        for (key, value) in credentials.as_object().unwrap() {
+            mqtt[key] = value.clone();
+        }
+        serde_json::from_value(mqtt).unwrap()
+    }
+
+    ///
+    /// starts a session against a local stub broker, returning the connection of the broker
+    /// after the subscription and the messages handled by the session
+    ///
+    async fn connected_session(limits: LimitsConfig) -> (TcpStream, Handled) {
+        let handled: Handled = Arc::new(Mutex::new(vec![]));
+        let recorded = handled.clone();
+        let broker = connected(
+            serde_json::json!({}),
+            limits,
+            move |route, topic, payload| {
+                recorded.lock().unwrap().push((route, topic, payload));
+                async { Ok(()) }
+            },
+        )
+        .await;
+        (broker, handled)
+    }
+
+    ///
+    /// starts a session with the settings and the handler against a local stub broker,
+    /// returning the connection of the broker after the subscription
+    ///
+    async fn connected<F, Fut>(
+        settings: serde_json::Value,
+        limits: LimitsConfig,
+        handle: F,
+    ) -> TcpStream
+    where
+        F: FnMut(&'static str, String, Vec<u8>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mqtt = mqtt_config(listener.local_addr().unwrap().port(), settings);
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            let _ = session(stream, &mqtt, &limits, handle).await;
+        });
+
+        let (mut broker, _) = listener.accept().await.unwrap();
+        let (header, _) = read_packet(&mut broker).await.unwrap();
+        assert_eq!(header, CONNECT);
+        broker.write_all(&[CONNACK, 2, 0, 0]).await.unwrap();
+        let (header, _) = read_packet(&mut broker).await.unwrap();
+        assert_eq!(header, SUBSCRIBE & 0xF0);
+        broker.write_all(&[SUBACK, 4, 0, 1, 1, 1]).await.unwrap();
+        broker
+    }
+
+    fn publish(topic: &str, qos: u8, id: u16, dup: bool, payload: &[u8]) -> Vec<u8> {
+        let mut body = vec![];
+        push_str(&mut body, topic);
+        if qos > 0 {
+            body.extend(&id.to_be_bytes());
+        }
+        body.extend(payload);
+        let dup = if dup { 0x08 } else { 0 };
+        packet(PUBLISH | dup | (qos << 1), body)
+    }
+
+    async fn expect(broker: &mut TcpStream, header: u8, id: u16) {
+        let (received, body) = tokio::time::timeout(Duration::from_secs(2), read_packet(broker))
+            .await
+            .expect("answer from the session")
+            .unwrap();
+        assert_eq!((received, body), (header, id.to_be_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn qos1_message_is_handled_and_acknowledged() {
+        let (mut broker, handled) = connected_session(LimitsConfig::default()).await;
+        broker
+            .write_all(&publish("iot2tangle/D1/sensor_data", 1, 7, false, b"{}"))
+            .await
+            .unwrap();
+        expect(&mut broker, PUBACK, 7).await;
+        broker
+            .write_all(&publish("iot2tangle/D1/bundle_data", 0, 0, false, b"[]"))
+            .await
+            .unwrap();
+        broker
+            .write_all(&publish("iot2tangle/D1/sensor_data", 1, 8, false, b"{}"))
+            .await
+            .unwrap();
+        expect(&mut broker, PUBACK, 8).await;
+
+        let handled = handled.lock().unwrap();
+        assert_eq!(handled.len(), 3);
+        assert_eq!(handled[0].0, "/sensor_data");
+        assert_eq!(
+            handled[1],
+            (
+                "/bundle_data",
+                "iot2tangle/D1/bundle_data".to_string(),
+                b"[]".to_vec()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn qos2_message_is_handled_once() {
+        let (mut broker, handled) = connected_session(LimitsConfig::default()).await;
+        let message = publish("iot2tangle/D1/sensor_data", 2, 9, false, b"{}");
+        broker.write_all(&message).await.unwrap();
+        expect(&mut broker, PUBREC, 9).await;
+        // redelivered before the release, acknowledged again but not handled
+        broker
+            .write_all(&publish("iot2tangle/D1/sensor_data", 2, 9, true, b"{}"))
+            .await
+            .unwrap();
+        expect(&mut broker, PUBREC, 9).await;
+        broker.write_all(&[PUBREL | 0x02, 2, 0, 9]).await.unwrap();
+        expect(&mut broker, PUBCOMP, 9).await;
+        assert_eq!(handled.lock().unwrap().len(), 1);
+
+        // once released the id can be used for a new message
+        broker.write_all(&message).await.unwrap();
+        expect(&mut broker, PUBREC, 9).await;
+        assert_eq!(handled.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn message_above_the_limit_is_skipped() {
+        let limits = LimitsConfig {
+            max_body_size: 16,
+            ..LimitsConfig::default()
+        };
+        let (mut broker, handled) = connected_session(limits).await;
+        broker
+            .write_all(&publish(
+                "iot2tangle/D1/sensor_data",
+                1,
+                1,
+                false,
+                &[b'x'; 17],
+            ))
+            .await
+            .unwrap();
+        expect(&mut broker, PUBACK, 1).await;
+        broker
+            .write_all(&publish(
+                "iot2tangle/D1/sensor_data",
+                1,
+                2,
+                false,
+                &[b'x'; 16],
+            ))
+            .await
+            .unwrap();
+        expect(&mut broker, PUBACK, 2).await;
+
+        let handled = handled.lock().unwrap();
+        assert_eq!(handled.len(), 1);
+        assert_eq!(handled[0].2.len(), 16);
+    }
+
+    #[tokio::test]
+    async fn broker_is_pinged_while_a_message_is_handled() {
+        let mut broker = connected(
+            serde_json::json!({"keep_alive": 1}),
+            LimitsConfig::default(),
+            |_, _, _| async {
+                tokio::time::delay_for(Duration::from_millis(2500)).await;
+                Ok(())
+            },
+        )
+        .await;
+        broker
+            .write_all(&publish("iot2tangle/D1/sensor_data", 1, 3, false, b"{}"))
+            .await
+            .unwrap();
+        let (header, _) =
+            tokio::time::timeout(Duration::from_millis(1500), read_packet(&mut broker))
+                .await
+                .expect("ping while handling")
+                .unwrap();
+        assert_eq!(header, PINGREQ);
+        let (header, _) = read_packet(&mut broker).await.unwrap();
+        assert_eq!(header, PINGREQ);
+        expect(&mut broker, PUBACK, 3).await;
+    }
+
+    #[tokio::test]
+    async fn failed_message_does_not_end_the_session() {
+        let mut broker = connected(
+            serde_json::json!({}),
+            LimitsConfig::default(),
+            |_, _, payload| async move {
+                if payload == b"fail" {
+                    return Err("publishing failed".into());
+                }
+                Ok(())
+            },
+        )
+        .await;
+        broker
+            .write_all(&publish("iot2tangle/D1/sensor_data", 1, 4, false, b"fail"))
+            .await
+            .unwrap();
+        broker
+            .write_all(&publish("iot2tangle/D1/sensor_data", 1, 5, false, b"{}"))
+            .await
+            .unwrap();
+        // acknowledged in the order they arrived
+        expect(&mut broker, PUBACK, 4).await;
+        expect(&mut broker, PUBACK, 5).await;
+    }
+
+    #[tokio::test]
+    async fn oversized_control_packet_ends_the_session() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mqtt = mqtt_config(listener.local_addr().unwrap().port(), serde_json::json!({}));
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut broker, _) = listener.accept().await.unwrap();
+        // a CONNACK claiming 256 MB is refused without reading or allocating it
+        broker
+            .write_all(&[CONNACK, 0xFF, 0xFF, 0xFF, 0x7F])
+            .await
+            .unwrap();
+        let result = session(stream, &mqtt, &LimitsConfig::default(), |_, _, _| async {
+            Ok(())
+        })
+        .await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("exceeds the limit"));
+    }
+
+    #[test]
+    fn password_is_sent_only_with_a_username() {
+        let connect = connect_packet(&mqtt_config(
+            1883,
+            serde_json::json!({"password": "secret"}),
+        ));
+        // flags follow the protocol name and level
+        assert_eq!(connect[2 + 7] & 0xC0, 0);
+        assert!(!String::from_utf8_lossy(&connect).contains("secret"));
+
+        let connect = connect_packet(&mqtt_config(
+            1883,
+            serde_json::json!({"username": "gateway", "password": "secret"}),
+        ));
+        assert_eq!(connect[2 + 7] & 0xC0, 0xC0);
+        assert!(String::from_utf8_lossy(&connect).ends_with("gateway\0\x06secret"));
+    }
+}
@@ -0,0 +1,339 @@
+use crate::device_auth::keystore::{authenticate, calculate_hash, KeyManager};
+use crate::monitoring::metrics;
+use crate::publishing::anchoring;
+use crate::publishing::batcher;
+use crate::publishing::retry::CircuitOpen;
+use crate::publishing::routing;
+use crate::shutdown;
+use crate::timestamp_in_sec;
+use crate::types::{
+    config::Config, gateway::Gateway, senml, sensor_data::SensorData, switch_auth::SwitchAuth,
+};
+use crate::wifi_connectivity::limits::check_message_size;
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use futures::{SinkExt, StreamExt};
+use hyper::upgrade::Upgraded;
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde_json::json;
+use tokio_tungstenite::tungstenite::protocol::{Message, Role, WebSocketConfig};
+use tokio_tungstenite::WebSocketStream;
+use tracing::{error, info, warn, Instrument, Span};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type Result<T> = std::result::Result<T, GenericError>;
+
+static WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// interval of the pings sent to the device, the connection is closed if nothing is received for two intervals
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// frames waiting to be published before the connection is no longer read
+const MAX_QUEUED: usize = 32;
+
+///
+/// Handles the WebSocket upgrade request. After the handshake the device authenticates once by sending
+/// {"device": "DEVICE_ID"} and can then stream data in the SensorData Format,
+/// every frame is acknowledged with the result of publishing it to the Tangle. Frames are published one after
+/// the other while the connection keeps being read and pinged, requests for a version other than 13
+/// are answered with 426 Upgrade Required
+///
+pub async fn websocket_response(
+    req: Request<Body>,
+    gateway: Arc<Gateway>,
+    keystore: Arc<Mutex<KeyManager>>,
+    config: Config,
+) -> Result<Response<Body>> {
+    let is_upgrade = req
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+    let key = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_KEY)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    let key = match key {
+        Some(key) if is_upgrade => key,
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Expected WebSocket upgrade request"))?)
+        }
+    };
+    let version = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .and_then(|value| value.to_str().ok());
+    if version.map(str::trim) != Some("13") {
+        return Ok(Response::builder()
+            .status(StatusCode::UPGRADE_REQUIRED)
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .body(Body::from("Unsupported WebSocket version - use version 13"))?);
+    }
+
+    tokio::spawn(
+        async move {
+            match req.into_body().on_upgrade().await {
+                Ok(upgraded) => serve(upgraded, gateway, keystore, config).await,
+                Err(e) => warn!(error = %e, "upgrade failed"),
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    let mut hasher = Sha1::new();
+    hasher.input_str(&key);
+    hasher.input_str(WEBSOCKET_GUID);
+    let mut accept = [0; 20];
+    hasher.result(&mut accept);
+
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::UPGRADE, "websocket")
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::SEC_WEBSOCKET_ACCEPT, base64::encode(&accept))
+        .body(Body::empty())?)
+}
+
+async fn serve(
+    upgraded: Upgraded,
+    gateway: Arc<Gateway>,
+    keystore: Arc<Mutex<KeyManager>>,
+    config: Config,
+) {
+    // frames are limited like request bodies, tungstenite would otherwise buffer messages of up to 64 MiB
+    let ws_config = WebSocketConfig {
+        max_send_queue: None,
+        max_message_size: Some(config.limits.max_body_size),
+        max_frame_size: Some(config.limits.max_body_size),
+    };
+    let mut ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(ws_config)).await;
+    let config = Arc::new(config);
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    let mut last_seen = Instant::now();
+    let mut device: Option<String> = None;
+    let mut queue: VecDeque<Vec<u8>> = VecDeque::new();
+    let mut publishing: Option<Pin<Box<dyn Future<Output = Message> + Send>>> = None;
+
+    loop {
+        if publishing.is_none() {
+            if let (Some(data), Some(device)) = (queue.pop_front(), &device) {
+                let (device, gateway, config) = (device.clone(), gateway.clone(), config.clone());
+                publishing = Some(Box::pin(async move {
+                    publish_frame(&data, &device, &gateway, &config).await
+                }));
+            }
+        }
+
+        let reply = tokio::select! {
+            frame = ws.next(), if queue.len() < MAX_QUEUED => {
+                last_seen = Instant::now();
+                let data = match frame {
+                    Some(Ok(Message::Text(text))) => text.into_bytes(),
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) => break,
+                };
+                if device.is_some() {
+                    queue.push_back(data);
+                    continue;
+                }
+                let (reply, authorized) = authenticate_frame(&data, &keystore);
+                device = authorized;
+                reply
+            }
+            reply = async { publishing.as_mut().unwrap().await }, if publishing.is_some() => {
+                publishing = None;
+                reply
+            }
+            _ = shutdown::requested() => {
+                info!("closing connection for shutdown");
+                break;
+            }
+            _ = ping.tick() => {
+                if last_seen.elapsed() > PING_INTERVAL * 2 {
+                    warn!("connection timed out");
+                    break;
+                }
+                Message::Ping(vec![])
+            }
+        };
+
+        let close = match &reply {
+            Message::Text(_) => device.is_none(),
+            _ => false,
+        };
+        if ws.send(reply).await.is_err() {
+            break;
+        }
+        if close {
+            break;
+        }
+    }
+    let _ = ws.close(None).await;
+}
+
+///
+/// checks the first frame sent by the device, returns the acknowledgement and the device name if it is whitelisted
+///
+fn authenticate_frame(data: &[u8], keystore: &Arc<Mutex<KeyManager>>) -> (Message, Option<String>) {
+    let device_auth: SwitchAuth = match serde_json::from_slice(data) {
+        Ok(device_auth) => device_auth,
+        Err(_) => {
+            return (
+                ack(
+                    "ERROR",
+                    "Malformed json - authenticate with {\"device\": \"DEVICE_ID\"}",
+                ),
+                None,
+            )
+        }
+    };
+    let hashes = keystore
+        .lock()
+        .expect("lock keystore")
+        .keystore
+        .api_keys_author
+        .clone();
+    if authenticate(&device_auth.device, hashes) {
+        info!(device = %calculate_hash(device_auth.device.clone()), "authorized connection by device");
+        (ack("AUTHORIZED", ""), Some(device_auth.device))
+    } else {
+        metrics::AUTH_FAILURES
+            .with_label_values(&["ws", "/ws"])
+            .inc();
+        warn!(device = %calculate_hash(device_auth.device.clone()), "unauthorized connection blocked");
+        (
+            ack(
+                "UNAUTHORIZED",
+                "Unauthorized - Device Name sent by device doesn't match the configuration",
+            ),
+            None,
+        )
+    }
+}
+
+///
+/// publishes a frame in the SensorData Format on behalf of the authenticated device
+///
+async fn publish_frame(
+    data: &[u8],
+    device: &str,
+    gateway: &Arc<Gateway>,
+    config: &Config,
+) -> Message {
+    metrics::BYTES_INGESTED.inc_by(data.len() as u64);
+    let mut sensor_data: SensorData = match serde_json::from_slice(data) {
+        Ok(sensor_data) => sensor_data,
+        Err(_) => return ack("ERROR", "Malformed json - use iot2tangle json format"),
+    };
+    sensor_data.device = calculate_hash(device.to_string());
+    metrics::device_seen(&sensor_data.device);
+    sensor_data.timestamp = serde_json::Value::from(timestamp_in_sec());
+
+    gateway.field_encryption.apply(&mut sensor_data);
+    if config.batching.is_some() {
+        // a reading too large for a message would fail the whole bundle
+        let oversized = if config.publish_senml {
+            check_message_size(&senml::sensor_data_to_pack(&sensor_data), &config.limits)
+        } else {
+            check_message_size(&sensor_data, &config.limits)
+        };
+        if let Some(size) = oversized {
+            warn!(size, "message exceeds the Streams payload size");
+            return too_large(size, config);
+        }
+        return match batcher::accept(gateway, config, sensor_data) {
+            Some(receipt) => Message::Text(
+                json!({ "status": "PENDING", "receipt": receipt.receipt }).to_string(),
+            ),
+            None => {
+                warn!("reading rejected, the batch is full");
+                ack("ERROR", batcher::BATCH_FULL)
+            }
+        };
+    }
+    if config.anchoring.is_some() {
+        return match anchoring::store(gateway, vec![sensor_data]).await {
+            Ok(reading_ids) => Message::Text(
+                json!({ "status": "PENDING", "reading_id": reading_ids[0] }).to_string(),
+            ),
+            Err(e) => {
+                error!(error = %e, "could not store reading");
+                ack("ERROR", "Could not store reading")
+            }
+        };
+    }
+    let channels = match routing::publish(gateway, &sensor_data, device, config).await {
+        Ok(channels) => channels,
+        Err(size) => {
+            warn!(size, "message exceeds the Streams payload size");
+            return too_large(size, config);
+        }
+    };
+    let failed = channels
+        .iter()
+        .filter(|(_, result)| result.is_err())
+        .count();
+    if failed < channels.len() {
+        if failed > 0 {
+            warn!("data published on some of the channels only");
+        }
+        return match &channels[..] {
+            [(_, Ok(channel_id))] if config.routing.is_empty() => ack("OK", channel_id),
+            _ => Message::Text(
+                json!({
+                    "status": if failed > 0 { "PARTIAL" } else { "OK" },
+                    "channels": routing::channel_results(&channels),
+                })
+                .to_string(),
+            ),
+        };
+    }
+    match channels.into_iter().next().map(|(_, result)| result) {
+        Some(Err(e)) if e.downcast_ref::<CircuitOpen>().is_some() => {
+            warn!("publishing rejected, circuit breaker is open");
+            ack(
+                "ERROR",
+                "IOTA Node unavailable after repeated failures, try again later!",
+            )
+        }
+        Some(Err(e)) => {
+            error!(error = %e, "could not publish to IOTA node");
+            ack(
+                "ERROR",
+                "Could not connect to IOTA Node, try with another node!",
+            )
+        }
+        _ => ack("ERROR", "No channel selected by the routing rules"),
+    }
+}
+
+fn too_large(size: usize, config: &Config) -> Message {
+    ack(
+        "ERROR",
+        &format!(
+            "Payload too large - the message has {} bytes, a Streams message can hold {} bytes",
+            size, config.limits.max_message_size
+        ),
+    )
+}
+
+fn ack(status: &str, detail: &str) -> Message {
+    let ack = match status {
+        "OK" => json!({ "status": status, "channel_id": detail }),
+        _ if detail.is_empty() => json!({ "status": status }),
+        _ => json!({ "status": status, "message": detail }),
+    };
+    Message::Text(ack.to_string())
+}